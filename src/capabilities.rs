@@ -0,0 +1,125 @@
+/// Name of the extended attribute that holds the file capabilities of a binary
+pub const CAPABILITY_XATTR: &str = "security.capability";
+
+// Indexed by capability number, see linux/capability.h
+const CAPABILITY_NAMES: [&str; 41] = [
+    "cap_chown",
+    "cap_dac_override",
+    "cap_dac_read_search",
+    "cap_fowner",
+    "cap_fsetid",
+    "cap_kill",
+    "cap_setgid",
+    "cap_setuid",
+    "cap_setpcap",
+    "cap_linux_immutable",
+    "cap_net_bind_service",
+    "cap_net_broadcast",
+    "cap_net_admin",
+    "cap_net_raw",
+    "cap_ipc_lock",
+    "cap_ipc_owner",
+    "cap_sys_module",
+    "cap_sys_rawio",
+    "cap_sys_chroot",
+    "cap_sys_ptrace",
+    "cap_sys_pacct",
+    "cap_sys_admin",
+    "cap_sys_boot",
+    "cap_sys_nice",
+    "cap_sys_resource",
+    "cap_sys_time",
+    "cap_sys_tty_config",
+    "cap_mknod",
+    "cap_lease",
+    "cap_audit_write",
+    "cap_audit_control",
+    "cap_setfcap",
+    "cap_mac_override",
+    "cap_mac_admin",
+    "cap_syslog",
+    "cap_wake_alarm",
+    "cap_block_suspend",
+    "cap_audit_read",
+    "cap_perfmon",
+    "cap_bpf",
+    "cap_checkpoint_restore",
+];
+
+const VFS_CAP_REVISION_MASK: u32 = 0xff00_0000;
+const VFS_CAP_REVISION_1: u32 = 0x0100_0000;
+const VFS_CAP_FLAGS_EFFECTIVE: u32 = 0x0000_0001;
+
+fn read_u32(value: &[u8], offset: usize) -> Option<u32> {
+    let bytes = value.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes(bytes.try_into().ok()?))
+}
+
+/// Decodes the value of the `security.capability` attribute into the form `getcap` uses,
+/// e.g. `cap_net_admin,cap_net_raw=ep`. Returns `None` if the value is malformed.
+pub fn describe_capabilities(value: &[u8]) -> Option<String> {
+    let magic = read_u32(value, 0)?;
+    // Revision 1 has 32 bit sets, later revisions 64 bit ones split into two words
+    let words = if magic & VFS_CAP_REVISION_MASK == VFS_CAP_REVISION_1 { 1 } else { 2 };
+
+    let mut permitted: u64 = 0;
+    let mut inheritable: u64 = 0;
+    for word in 0..words {
+        permitted |= (read_u32(value, 4 + word * 8)? as u64) << (32 * word);
+        inheritable |= (read_u32(value, 8 + word * 8)? as u64) << (32 * word);
+    }
+
+    let names: Vec<String> = (0..64)
+        .filter(|bit| (permitted | inheritable) & (1 << bit) != 0)
+        .map(|bit| match CAPABILITY_NAMES.get(bit) {
+            Some(name) => name.to_string(),
+            None => format!("cap_{}", bit),
+        })
+        .collect();
+
+    let mut flags = String::new();
+    if magic & VFS_CAP_FLAGS_EFFECTIVE != 0 {
+        flags.push('e');
+    }
+    if inheritable != 0 {
+        flags.push('i');
+    }
+    if permitted != 0 {
+        flags.push('p');
+    }
+    Some(format!("{}={}", names.join(","), flags))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capability_value(magic: u32, words: &[u32]) -> Vec<u8> {
+        std::iter::once(magic)
+            .chain(words.iter().copied())
+            .flat_map(|word| word.to_le_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn ping_capabilities() {
+        // What `setcap cap_net_raw+ep` writes
+        let value = capability_value(0x0200_0001, &[1 << 13, 0, 0, 0]);
+        assert_eq!(describe_capabilities(&value).as_deref(), Some("cap_net_raw=ep"));
+    }
+
+    #[test]
+    fn capabilities_of_both_words() {
+        let value = capability_value(0x0200_0000, &[(1 << 12) | (1 << 13), 0, 1 << 7, 1 << 7]);
+        assert_eq!(
+            describe_capabilities(&value).as_deref(),
+            Some("cap_net_admin,cap_net_raw,cap_bpf=ip")
+        );
+    }
+
+    #[test]
+    fn malformed_capabilities() {
+        assert_eq!(describe_capabilities(&[1, 2]), None);
+        assert_eq!(describe_capabilities(&capability_value(0x0200_0000, &[1])), None);
+    }
+}
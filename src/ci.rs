@@ -0,0 +1,305 @@
+use crate::efficiency::Efficiency;
+use crate::exceptions::{ImageParcingError, PathContext};
+use crate::file_names::escape_name;
+use crate::file_tree::format_size;
+use crate::query::parse_size;
+use serde::Deserialize;
+use serde::de::{self, Deserializer};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Where the rules are looked for when no config is given
+pub const DEFAULT_CONFIG: &str = ".freightview.yaml";
+
+/// Contents of `.freightview.yaml`
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct CiConfig {
+    #[serde(default)]
+    pub rules: CiRules,
+}
+
+/// Limits the image has to stay within; rules that are not set are not checked.
+/// Shares are given from 0 to 1 and sizes in bytes or with a unit, e.g. `20MB`.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct CiRules {
+    #[serde(default, deserialize_with = "deserialize_share")]
+    pub lowest_efficiency: Option<f64>,
+    #[serde(default, deserialize_with = "deserialize_size")]
+    pub highest_wasted_bytes: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_share")]
+    pub highest_wasted_percent: Option<f64>,
+    #[serde(default, deserialize_with = "deserialize_size")]
+    pub max_image_size: Option<u64>,
+}
+
+fn deserialize_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        WithUnit(String),
+    }
+    match Option::<Size>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(Size::Bytes(bytes)) => Ok(Some(bytes)),
+        Some(Size::WithUnit(size)) => parse_size(size.trim()).map(Some).map_err(de::Error::custom),
+    }
+}
+
+fn deserialize_share<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<f64>, D::Error> {
+    match Option::<f64>::deserialize(deserializer)? {
+        // A percentage instead of a share would make the rule always pass or always fail
+        Some(share) if !(0.0..=1.0).contains(&share) => Err(de::Error::custom(format!(
+            "{} is not a share from 0 to 1",
+            share
+        ))),
+        share => Ok(share),
+    }
+}
+
+impl CiConfig {
+    /// Reads the rules from `path`, or from `.freightview.yaml` in the current directory.
+    /// Without either the same defaults as dive's are used.
+    pub fn load(path: Option<&Path>) -> Result<CiConfig, ImageParcingError> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None if Path::new(DEFAULT_CONFIG).exists() => PathBuf::from(DEFAULT_CONFIG),
+            None => {
+                return Ok(CiConfig {
+                    rules: CiRules {
+                        lowest_efficiency: Some(0.9),
+                        highest_wasted_percent: Some(0.1),
+                        ..CiRules::default()
+                    },
+                });
+            }
+        };
+        let config = std::fs::read_to_string(&path).with_path(&path)?;
+        CiConfig::parse(&config).map_err(|source| ImageParcingError::CiConfigError { path, source })
+    }
+
+    pub fn parse(config: &str) -> Result<CiConfig, serde_norway::Error> {
+        // An empty file has no rules rather than being invalid
+        if config.trim().is_empty() {
+            return Ok(CiConfig::default());
+        }
+        serde_norway::from_str(config)
+    }
+}
+
+/// Which side of the limit the image has to stay on
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Bound {
+    AtLeast,
+    AtMost,
+}
+
+/// Outcome of one rule
+#[derive(Debug, PartialEq)]
+pub struct RuleResult {
+    pub rule: &'static str,
+    pub bound: Bound,
+    pub passed: bool,
+    /// The value of the image and the limit, as shown in the report
+    pub actual: String,
+    pub limit: String,
+}
+
+fn percent(share: f64) -> String {
+    format!("{:.1}%", share * 100.0)
+}
+
+/// Checks the image against every rule that is set. Layers that failed to load, by label,
+/// fail the check on their own since the analysis misses their contents.
+pub fn evaluate(rules: &CiRules, efficiency: &Efficiency, failed_layers: &[String]) -> Vec<RuleResult> {
+    let wasted_share = if efficiency.total_bytes == 0 {
+        0.0
+    } else {
+        efficiency.wasted_bytes as f64 / efficiency.total_bytes as f64
+    };
+    let mut results = Vec::new();
+    if !failed_layers.is_empty() {
+        results.push(RuleResult {
+            rule: "failedLayers",
+            bound: Bound::AtMost,
+            passed: false,
+            actual: format!("{} ({})", failed_layers.len(), failed_layers.join(", ")),
+            limit: "0".to_string(),
+        });
+    }
+    if let Some(lowest) = rules.lowest_efficiency {
+        results.push(RuleResult {
+            rule: "lowestEfficiency",
+            bound: Bound::AtLeast,
+            passed: efficiency.score() >= lowest,
+            actual: percent(efficiency.score()),
+            limit: percent(lowest),
+        });
+    }
+    if let Some(highest) = rules.highest_wasted_bytes {
+        results.push(RuleResult {
+            rule: "highestWastedBytes",
+            bound: Bound::AtMost,
+            passed: efficiency.wasted_bytes <= highest,
+            actual: format_size(efficiency.wasted_bytes),
+            limit: format_size(highest),
+        });
+    }
+    if let Some(highest) = rules.highest_wasted_percent {
+        results.push(RuleResult {
+            rule: "highestWastedPercent",
+            bound: Bound::AtMost,
+            passed: wasted_share <= highest,
+            actual: percent(wasted_share),
+            limit: percent(highest),
+        });
+    }
+    if let Some(max) = rules.max_image_size {
+        results.push(RuleResult {
+            rule: "maxImageSize",
+            bound: Bound::AtMost,
+            passed: efficiency.total_bytes <= max,
+            actual: format_size(efficiency.total_bytes),
+            limit: format_size(max),
+        });
+    }
+    results
+}
+
+/// Paths that waste the most, listed after failures to show where to start
+const REPORTED_PATHS: usize = 10;
+
+/// Prints the analysis and the outcome of every rule
+pub fn write_report(
+    out: &mut impl Write,
+    image: &str,
+    efficiency: &Efficiency,
+    results: &[RuleResult],
+) -> io::Result<()> {
+    writeln!(out, "Image: {}", image)?;
+    writeln!(
+        out,
+        "Efficiency {}, stored {}, wasted {}, duplicated {}",
+        percent(efficiency.score()),
+        format_size(efficiency.total_bytes),
+        format_size(efficiency.wasted_bytes),
+        format_size(efficiency.duplicate_bytes())
+    )?;
+    if results.is_empty() {
+        writeln!(out, "No rules configured")?;
+    }
+    for result in results {
+        let (status, relation) = match (result.passed, result.bound) {
+            (true, Bound::AtLeast) => ("PASS", ">="),
+            (false, Bound::AtLeast) => ("FAIL", "<"),
+            (true, Bound::AtMost) => ("PASS", "<="),
+            (false, Bound::AtMost) => ("FAIL", ">"),
+        };
+        writeln!(
+            out,
+            "  {}  {}: {} {} {}",
+            status, result.rule, result.actual, relation, result.limit
+        )?;
+    }
+
+    let n_failed = results.iter().filter(|result| !result.passed).count();
+    if n_failed > 0 && !efficiency.wasted_paths.is_empty() {
+        writeln!(out, "Paths that waste the most:")?;
+        for wasted in efficiency.wasted_paths.iter().take(REPORTED_PATHS) {
+            writeln!(
+                out,
+                "  {:>9}  {}",
+                format_size(wasted.wasted),
+                escape_name(wasted.path.as_os_str())
+            )?;
+        }
+    }
+    if n_failed == 0 {
+        writeln!(out, "Result: PASS")
+    } else {
+        writeln!(out, "Result: FAIL ({} of {} rules failed)", n_failed, results.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::efficiency::WastedPath;
+
+    fn efficiency(total_bytes: u64, wasted_bytes: u64) -> Efficiency {
+        Efficiency {
+            total_bytes,
+            wasted_bytes,
+            wasted_paths: vec![WastedPath {
+                path: PathBuf::from("/var/cache/apt/archives/big.deb"),
+                wasted: wasted_bytes,
+                written_by: vec![1],
+                dropped_by: Some(2),
+            }],
+            duplicates: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn config() {
+        let config = CiConfig::parse(
+            "rules:\n  lowestEfficiency: 0.95\n  highestWastedBytes: 20MB\n  maxImageSize: 1048576\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.rules,
+            CiRules {
+                lowest_efficiency: Some(0.95),
+                highest_wasted_bytes: Some(20 << 20),
+                highest_wasted_percent: None,
+                max_image_size: Some(1 << 20),
+            }
+        );
+        assert_eq!(CiConfig::parse("").unwrap(), CiConfig::default());
+        assert!(CiConfig::parse("rules:\n  lowestEfficency: 0.9\n").is_err());
+        assert!(CiConfig::parse("rules:\n  highestWastedBytes: 20 parsecs\n").is_err());
+        assert!(CiConfig::parse("rules:\n  highestWastedPercent: 5\n").is_err());
+        assert!(CiConfig::parse("rules:\n  lowestEfficiency: -0.1\n").is_err());
+    }
+
+    #[test]
+    fn rules() {
+        let rules = CiRules {
+            lowest_efficiency: Some(0.9),
+            highest_wasted_bytes: Some(100),
+            highest_wasted_percent: Some(0.05),
+            max_image_size: Some(2000),
+        };
+        let passed = |efficiency: &Efficiency| -> Vec<bool> {
+            evaluate(&rules, efficiency, &[]).iter().map(|result| result.passed).collect()
+        };
+        assert_eq!(passed(&efficiency(1000, 10)), [true, true, true, true]);
+        assert_eq!(passed(&efficiency(1000, 60)), [true, true, false, true]);
+        assert_eq!(passed(&efficiency(4000, 1000)), [false, false, false, false]);
+        assert!(evaluate(&CiRules::default(), &efficiency(1000, 1000), &[]).is_empty());
+
+        let mut out = Vec::new();
+        let efficiency = efficiency(1000, 60);
+        write_report(&mut out, "app:1.3", &efficiency, &evaluate(&rules, &efficiency, &[])).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        assert!(report.contains("  FAIL  highestWastedPercent: 6.0% > 5.0%\n"));
+        assert!(report.contains("  PASS  lowestEfficiency: 94.0% >= 90.0%\n"));
+        assert!(report.contains("/var/cache/apt/archives/big.deb"));
+        assert!(report.ends_with("Result: FAIL (1 of 4 rules failed)\n"));
+    }
+
+    #[test]
+    fn failed_layers() {
+        let efficiency = efficiency(1000, 10);
+        let results = evaluate(&CiRules::default(), &efficiency, &["#2".to_string(), "#5".to_string()]);
+        assert_eq!(results.iter().map(|result| result.passed).collect::<Vec<bool>>(), [false]);
+
+        let mut out = Vec::new();
+        write_report(&mut out, "app:1.3", &efficiency, &results).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        assert!(report.contains("  FAIL  failedLayers: 2 (#2, #5) > 0\n"));
+        assert!(report.ends_with("Result: FAIL (1 of 1 rules failed)\n"));
+    }
+}
@@ -0,0 +1,191 @@
+use std::cell::Cell;
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Directories that only live for the duration of the run, e.g. the exported image.
+// They are removed on normal exit by their owners, but a panic or a signal skips that,
+// so they are tracked here as well.
+static STAGING_DIRS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+static TERMINAL_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    // Set while a panic on this thread is caught by `catch_panic` and the run goes on
+    static PANIC_IS_CAUGHT: Cell<bool> = const { Cell::new(false) };
+}
+
+const EXIT_CODE_SIGINT: i32 = 130;
+const EXIT_CODE_SIGTERM: i32 = 143;
+
+pub fn register_staging_dir(path: &Path) {
+    let mut dirs = STAGING_DIRS.lock().unwrap_or_else(|e| e.into_inner());
+    if !dirs.iter().any(|dir| dir == path) {
+        dirs.push(path.to_path_buf());
+    }
+}
+
+pub fn unregister_staging_dir(path: &Path) {
+    let mut dirs = STAGING_DIRS.lock().unwrap_or_else(|e| e.into_inner());
+    dirs.retain(|dir| dir != path);
+}
+
+/// Removes all registered staging directories, returns the ones that were removed
+pub fn remove_staging_dirs() -> Vec<PathBuf> {
+    let mut dirs = STAGING_DIRS.lock().unwrap_or_else(|e| e.into_inner());
+    let mut removed: Vec<PathBuf> = Vec::new();
+    for dir in dirs.drain(..) {
+        if dir.exists() && std::fs::remove_dir_all(&dir).is_ok() {
+            removed.push(dir);
+        }
+    }
+    removed
+}
+
+pub fn init_terminal() -> ratatui::DefaultTerminal {
+    TERMINAL_ACTIVE.store(true, Ordering::SeqCst);
+    ratatui::init()
+}
+
+/// Leaves raw mode and the alternate screen; does nothing if the terminal was not initialised
+pub fn restore_terminal() {
+    if TERMINAL_ACTIVE.swap(false, Ordering::SeqCst) {
+        ratatui::restore();
+    }
+}
+
+fn report_removed_dirs(removed: &[PathBuf]) {
+    for dir in removed {
+        eprintln!("Removed temporary directory {}", dir.display());
+    }
+}
+
+/// Whether a panic right now ends the run. Panics of other threads are caught by whoever
+/// joins them, e.g. the layer parser or tokio, and the run goes on with the staging directories.
+fn panic_ends_run() -> bool {
+    !PANIC_IS_CAUGHT.get() && std::thread::current().name() == Some("main")
+}
+
+/// Installs a panic hook that restores the terminal and removes staging directories before
+/// printing the panic, so a crash does not leave the terminal in raw mode or gigabytes of
+/// exported images on the disk
+pub fn install_panic_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        // The staging directories are still in use by the rest of the run
+        if !panic_ends_run() {
+            default_hook(info);
+            return;
+        }
+        restore_terminal();
+        let removed = remove_staging_dirs();
+
+        eprintln!("FreightView crashed, this is a bug.");
+        report_removed_dirs(&removed);
+        eprintln!(
+            "Please report it at {}/issues together with the message below; \
+             set RUST_BACKTRACE=1 to include a backtrace.",
+            env!("CARGO_PKG_REPOSITORY")
+        );
+        default_hook(info);
+    }));
+}
+
+/// Runs `f` and turns a panic into an error with the panic message, for work like parsing
+/// a single layer whose failure should not bring down the whole run
+pub fn catch_panic<T>(f: impl FnOnce() -> T) -> Result<T, String> {
+    let was_caught = PANIC_IS_CAUGHT.replace(true);
+    let result = std::panic::catch_unwind(AssertUnwindSafe(f));
+    PANIC_IS_CAUGHT.set(was_caught);
+    result.map_err(|payload| {
+        payload
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| String::from("unknown panic"))
+    })
+}
+
+/// Spawns a task that restores the terminal, removes staging directories and exits
+/// when the process receives SIGINT or SIGTERM
+#[cfg(unix)]
+pub fn spawn_signal_handler() {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    tokio::spawn(async {
+        let (Ok(mut sigint), Ok(mut sigterm)) = (
+            signal(SignalKind::interrupt()),
+            signal(SignalKind::terminate()),
+        ) else {
+            eprintln!("Can't install signal handlers, temporary files may stay on interrupt");
+            return;
+        };
+
+        let (name, exit_code) = tokio::select! {
+            _ = sigint.recv() => ("SIGINT", EXIT_CODE_SIGINT),
+            _ = sigterm.recv() => ("SIGTERM", EXIT_CODE_SIGTERM),
+        };
+
+        restore_terminal();
+        let removed = remove_staging_dirs();
+        eprintln!("Received {}, exiting", name);
+        report_removed_dirs(&removed);
+        std::process::exit(exit_code);
+    });
+}
+
+#[cfg(not(unix))]
+pub fn spawn_signal_handler() {
+    tokio::spawn(async {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            restore_terminal();
+            let removed = remove_staging_dirs();
+            eprintln!("Interrupted, exiting");
+            report_removed_dirs(&removed);
+            std::process::exit(EXIT_CODE_SIGINT);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn staging_dirs_are_removed() {
+        let dir = TempDir::new().unwrap();
+        let staging = dir.path().join("staging");
+        let kept = dir.path().join("kept");
+        std::fs::create_dir(&staging).unwrap();
+        std::fs::create_dir(&kept).unwrap();
+
+        register_staging_dir(&staging);
+        register_staging_dir(&kept);
+        unregister_staging_dir(&kept);
+        let removed = remove_staging_dirs();
+
+        assert!(removed.contains(&staging));
+        assert!(!staging.exists());
+        assert!(kept.exists());
+    }
+
+    #[test]
+    fn caught_panics_become_errors() {
+        assert_eq!(catch_panic(|| 42), Ok(42));
+        assert_eq!(catch_panic(|| -> u32 { panic!("bad layer") }), Err(String::from("bad layer")));
+        let index = 7;
+        assert_eq!(
+            catch_panic(|| -> u32 { panic!("bad entry {}", index) }),
+            Err(String::from("bad entry 7"))
+        );
+        assert!(!PANIC_IS_CAUGHT.get());
+    }
+
+    #[test]
+    fn only_main_thread_panics_end_the_run() {
+        assert_eq!(catch_panic(panic_ends_run), Ok(false));
+        let worker = std::thread::Builder::new().name(String::from("worker"));
+        assert!(!worker.spawn(panic_ends_run).unwrap().join().unwrap());
+    }
+}
@@ -1,3 +1,4 @@
+use crate::cleanup;
 use crate::file_tree::FileTree;
 use bollard::{Docker, image::ListImagesOptions, secret::ImageSummary};
 use futures_core::task::Poll;
@@ -5,35 +6,81 @@ use futures_util::StreamExt;
 use home::home_dir;
 use std::fs::File;
 use std::io::Write;
-use std::path::PathBuf;
-use std::{io::Read, mem::swap};
+use std::path::{Path, PathBuf};
+use std::mem::swap;
 use tar::Archive;
 use tempfile::TempDir;
 
-use crate::exceptions::ImageParcingError;
-use serde::Deserialize;
+use crate::exceptions::{ImageParcingError, PathContext};
+use crate::layer_digest::{HashingReader, open_layer_blob};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Version of the format of the cached layer trees and image metadata. Entries of other
+/// versions live in their own directories and are not read; bump it whenever the stored
+/// data changes meaning.
+const CACHE_FORMAT_VERSION: u32 = 7;
 
 pub struct ImageLayer {
     pub name: String,
     pub tree: FileTree,
     pub command: String,
+    pub created: Option<String>,
+    /// Set when the layer could not be parsed or loaded; the tree is empty in this case
+    pub error: Option<String>,
 }
 
 impl ImageLayer {
-    pub fn new(name: String, tree: FileTree, command: String) -> ImageLayer {
+    pub fn new(name: String, tree: FileTree, command: String, created: Option<String>) -> ImageLayer {
         ImageLayer {
             name,
             tree,
             command,
+            created,
+            error: None,
+        }
+    }
+
+    /// How a layer is referred to in every view and report: its index from the bottom of the
+    /// image, starting at 0, as shown in the layer list
+    pub fn label(index: usize) -> String {
+        format!("#{}", index)
+    }
+
+    /// Labels of the layers that failed to load
+    pub fn failed_labels(layers: &[ImageLayer]) -> Vec<String> {
+        layers
+            .iter()
+            .enumerate()
+            .filter(|(_, layer)| layer.error.is_some())
+            .map(|(index, _)| ImageLayer::label(index))
+            .collect()
+    }
+
+    /// First line of the command that created the layer, for listings
+    pub fn short_command(&self) -> &str {
+        self.command.lines().next().unwrap_or_default().trim()
+    }
+
+    /// Placeholder for a layer that failed to parse, so the rest of the image can still be shown
+    pub fn failed(layer: &LayerMetadata, error: &ImageParcingError) -> ImageLayer {
+        ImageLayer {
+            name: layer.diff_id.clone(),
+            tree: FileTree::empty(),
+            command: layer.command.clone(),
+            created: layer.created.clone(),
+            error: Some(error.describe()),
         }
     }
 
     fn get_cache_dir() -> Result<PathBuf, ImageParcingError> {
         let home = home_dir().ok_or(ImageParcingError::CantGetAHomeDir)?;
-        let cache_path = home.join(".cache/freightview");
+        let cache_path = home
+            .join(".cache/freightview")
+            .join(format!("v{}", CACHE_FORMAT_VERSION));
         if !cache_path.exists() {
-            std::fs::create_dir(&cache_path)?;
+            std::fs::create_dir_all(&cache_path).with_path(&cache_path)?;
         }
         Ok(cache_path)
     }
@@ -48,55 +95,53 @@ impl ImageLayer {
         ImageLayer::get_layer_path_wstr(&self.name)
     }
 
-    fn get_layer_path_cmd_wstr(layer: &str) -> Result<PathBuf, ImageParcingError> {
-        Ok(ImageLayer::get_cache_dir()?
-            .join(layer)
-            .with_extension("cmd"))
-    }
-
-    fn get_layer_path_cmd(&self) -> Result<PathBuf, ImageParcingError> {
-        Ok(ImageLayer::get_layer_path_cmd_wstr(&self.name)?)
-    }
-
+    /// Saves the layer tree to the cache. Only the tree is stored here, as it is fully determined
+    /// by the layer content; commands and dates belong to the image, see [`ImageMetadata`].
     pub fn save(&self) -> Result<(), ImageParcingError> {
-        // Serialise tree to json
         let layer_cache_path = self.get_layer_path()?;
-        let mut layer_cache_file =
-            File::create(&layer_cache_path).expect("Can't create layer cache file");
-        let layer_json = serde_json::to_string(&self.tree).expect("Can't serialize layer tree");
+        let layer_json = serde_json::to_string(&self.tree).with_path(&layer_cache_path)?;
+        // Write to a temporary file first so an interrupted write can't leave a truncated entry
+        let tmp_path = layer_cache_path.with_extension("json.tmp");
+        let mut layer_cache_file = File::create(&tmp_path).with_path(&tmp_path)?;
         layer_cache_file
             .write_all(layer_json.as_bytes())
-            .expect("Can't write to layer cache file");
-        // Serialise command to file
-        let cmd_cache_file = self.get_layer_path_cmd()?;
-        let mut cmd_cache_file =
-            File::create(&cmd_cache_file).expect("Can't create command cache file");
-        cmd_cache_file
-            .write_all(self.command.as_bytes())
-            .expect("Can't write to command cache file");
+            .with_path(&tmp_path)?;
+        std::fs::rename(&tmp_path, &layer_cache_path).with_path(&layer_cache_path)?;
         Ok(())
     }
 
-    pub fn load(layer: &str) -> Result<ImageLayer, ImageParcingError> {
+    /// Loads the layer tree from the cache and attaches the command that created it in this image
+    pub fn load(layer: &LayerMetadata) -> Result<ImageLayer, ImageParcingError> {
+        let layer_tree = ImageLayer::load_tree(&layer.diff_id)?;
+
+        Ok(ImageLayer::new(
+            layer.diff_id.clone(),
+            layer_tree,
+            layer.command.clone(),
+            layer.created.clone(),
+        ))
+    }
+
+    pub fn load_tree(layer: &str) -> Result<FileTree, ImageParcingError> {
         let layer_cache_path = ImageLayer::get_layer_path_wstr(layer)?;
-        let layer_cache_file = File::open(&layer_cache_path)?;
-        let layer_tree: FileTree = serde_json::from_reader(layer_cache_file)?;
-        let cmd_cache_file = ImageLayer::get_layer_path_cmd_wstr(layer)?;
-        let mut cmd_cache_file = File::open(&cmd_cache_file)?;
-        let mut command = String::new();
-        cmd_cache_file.read_to_string(&mut command)?;
+        let layer_cache_file = File::open(&layer_cache_path).with_path(&layer_cache_path)?;
+        let layer_tree: FileTree =
+            serde_json::from_reader(layer_cache_file).with_path(&layer_cache_path)?;
+        Ok(layer_tree)
+    }
 
-        Ok(ImageLayer::new(layer.to_string(), layer_tree, command))
+    pub fn remove_from_cache(layer: &str) -> Result<(), ImageParcingError> {
+        let layer_cache_path = ImageLayer::get_layer_path_wstr(layer)?;
+        if layer_cache_path.exists() {
+            std::fs::remove_file(&layer_cache_path).with_path(&layer_cache_path)?;
+        }
+        Ok(())
     }
 
     pub fn check_cache(layer: &str) -> bool {
         match ImageLayer::get_layer_path_wstr(layer) {
-            Ok(path) => {
-                return path.exists();
-            }
-            Err(_) => {
-                return false;
-            }
+            Ok(path) => path.exists(),
+            Err(_) => false,
         }
     }
 
@@ -104,34 +149,191 @@ impl ImageLayer {
     ///
     /// # Arguments
     /// * `layers` - A mutable reference to a vector of layer identifiers (strings).
+    ///   After the call it only contains the layers that are not cached.
     /// # Returns
-    /// * `Ok(Vec<ImageLayer>)` - A vector of `ImageLayer` objects representing the cached layers.
-    /// * `Err(ImageParcingError)` - An error if loading a cached layer fails.
-    pub fn filter_cached_layers(
-        layers: &mut Vec<String>,
-    ) -> Result<Vec<ImageLayer>, ImageParcingError> {
+    /// * A vector of layer identifiers that are already cached.
+    pub fn filter_cached_layers(layers: &mut Vec<String>) -> Vec<String> {
         let mut remaining_layers: Vec<String> = Vec::new();
         let mut cached_layers: Vec<String> = Vec::new();
-        let mut out_layers: Vec<ImageLayer> = Vec::new();
         for layer in &mut *layers {
             if ImageLayer::check_cache(layer) {
-                println!("Layer {} is cached", layer);
+                eprintln!("Layer {} is cached", layer);
                 cached_layers.push(layer.clone());
             } else {
-                println!("Layer {} is not cached", layer);
+                eprintln!("Layer {} is not cached", layer);
                 remaining_layers.push(layer.clone());
             }
         }
 
-        for layer in cached_layers {
-            let layer = ImageLayer::load(&layer)?;
-            out_layers.push(layer);
+        swap(layers, &mut remaining_layers);
+
+        cached_layers
+    }
+}
+
+/// Image-specific information about a single layer. The same layer (diff_id) can be
+/// created by different commands in different images, so this is stored per image.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct LayerMetadata {
+    pub diff_id: String,
+    pub command: String,
+    pub created: Option<String>,
+}
+
+/// Per-image metadata, stored in the cache under the image id.
+/// Layer trees are content-addressed and shared between images, this is not.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct ImageMetadata {
+    /// Id docker reports for the image; with the classic image store it is the config digest,
+    /// with the containerd image store it is the digest of the manifest
+    pub image_id: String,
+    pub config_digest: String,
+    pub layers: Vec<LayerMetadata>,
+}
+
+impl ImageMetadata {
+    fn get_metadata_dir() -> Result<PathBuf, ImageParcingError> {
+        let metadata_path = ImageLayer::get_cache_dir()?.join("images");
+        if !metadata_path.exists() {
+            std::fs::create_dir_all(&metadata_path).with_path(&metadata_path)?;
+        }
+        Ok(metadata_path)
+    }
+
+    fn get_metadata_path(image_id: &str) -> Result<PathBuf, ImageParcingError> {
+        Ok(ImageMetadata::get_metadata_dir()?
+            .join(image_id)
+            .with_extension("json"))
+    }
+
+    /// Reads the metadata from the config file of an exported image
+    pub fn from_export(
+        docker_root_folder: &Path,
+        config_file: &str,
+        image_id: &str,
+    ) -> Result<ImageMetadata, ImageParcingError> {
+        let config_path = docker_root_folder.join(config_file);
+        let config_file = File::open(&config_path).with_path(&config_path)?;
+        let config: Config = serde_json::from_reader(config_file).map_err(|source| {
+            ImageParcingError::ExportJSONError {
+                path: config_path.clone(),
+                source,
+            }
+        })?;
+        let config_digest = config_path
+            .file_name()
+            .map(|name| format!("sha256:{}", name.to_string_lossy()))
+            .ok_or_else(|| ImageParcingError::LayerParsingError {
+                path: config_path.clone(),
+                reason: String::from("config path has no file name"),
+            })?;
+        Ok(ImageMetadata::from_config(
+            image_id.to_string(),
+            config_digest,
+            config,
+        ))
+    }
+
+    fn from_config(image_id: String, config_digest: String, config: Config) -> ImageMetadata {
+        let layer_history: Vec<History> = config
+            .history
+            .into_iter()
+            .filter(|history| !history.empty_layer.unwrap_or(false))
+            .collect();
+
+        if layer_history.len() != config.rootfs.diff_ids.len() {
+            eprintln!(
+                "Image history has {} layers, but rootfs has {}; commands are not available",
+                layer_history.len(),
+                config.rootfs.diff_ids.len()
+            );
         }
 
-        swap(layers, &mut remaining_layers);
+        let mut history_iter = layer_history.into_iter();
+        let layers = config
+            .rootfs
+            .diff_ids
+            .into_iter()
+            .map(|diff_id| match history_iter.next() {
+                Some(history) => LayerMetadata {
+                    diff_id,
+                    command: history
+                        .created_by
+                        .unwrap_or(String::from("Unknown command"))
+                        .trim()
+                        .to_string(),
+                    created: history.created,
+                },
+                None => LayerMetadata {
+                    diff_id,
+                    command: String::from("Not available"),
+                    created: None,
+                },
+            })
+            .collect();
 
-        Ok(out_layers)
+        ImageMetadata {
+            image_id,
+            config_digest,
+            layers,
+        }
     }
+
+    pub fn save(&self) -> Result<(), ImageParcingError> {
+        let metadata_path = ImageMetadata::get_metadata_path(&self.image_id)?;
+        let metadata_file = File::create(&metadata_path).with_path(&metadata_path)?;
+        serde_json::to_writer(metadata_file, self).with_path(&metadata_path)?;
+        Ok(())
+    }
+
+    pub fn load(image_id: &str) -> Result<ImageMetadata, ImageParcingError> {
+        let metadata_path = ImageMetadata::get_metadata_path(image_id)?;
+        let metadata_file = File::open(&metadata_path).with_path(&metadata_path)?;
+        let metadata: ImageMetadata =
+            serde_json::from_reader(metadata_file).with_path(&metadata_path)?;
+        Ok(metadata)
+    }
+
+    pub fn check_cache(image_id: &str) -> bool {
+        match ImageMetadata::get_metadata_path(image_id) {
+            Ok(path) => path.exists(),
+            Err(_) => false,
+        }
+    }
+}
+
+/// Result of re-checking a cached layer against the image it came from
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum CacheEntryStatus {
+    Valid,
+    NotCached,
+    /// The blob does not hash to the diff_id; contains the digest that was computed
+    DigestMismatch(String),
+    /// The blob is fine, but the cached tree differs from the one built from it
+    TreeMismatch,
+    /// The blob can't be read or parsed, so the cached tree can't be checked; contains why
+    Unreadable(String),
+}
+
+impl std::fmt::Display for CacheEntryStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CacheEntryStatus::Valid => write!(f, "ok"),
+            CacheEntryStatus::NotCached => write!(f, "not cached"),
+            CacheEntryStatus::DigestMismatch(actual) => {
+                write!(f, "digest mismatch, layer hashes to {}; removed from cache", actual)
+            }
+            CacheEntryStatus::TreeMismatch => {
+                write!(f, "cached tree differs from layer content; removed from cache")
+            }
+            CacheEntryStatus::Unreadable(reason) => write!(f, "can't read the layer: {}", reason),
+        }
+    }
+}
+
+pub struct CacheEntryReport {
+    pub diff_id: String,
+    pub status: CacheEntryStatus,
 }
 
 pub struct ImageRepr {
@@ -144,83 +346,203 @@ pub struct ImageRepr {
 
 impl ImageRepr {
     pub async fn new(name: String, docker: &Docker) -> Result<ImageRepr, ImageParcingError> {
-        let temp_dir = TempDir::new()?;
-        let img_tar_file_path = ImageRepr::get_img_cache_dir(&name)?.join("image.tar");
-        let img_folder = ImageRepr::get_img_cache_dir(&name)?.join("image");
+        let mut temp_dir = TempDir::new().with_path(&std::env::temp_dir())?;
+        cleanup::register_staging_dir(temp_dir.path());
 
-        let layers = get_image_layers(&docker, &name).await?;
-        let all_layers_cached: bool = layers.iter().all(|layer| ImageLayer::check_cache(layer));
+        let (image_id, layers) = get_image_id_and_layers(docker, &name).await?;
+        let all_layers_cached: bool = ImageMetadata::check_cache(&image_id)
+            && layers.iter().all(|layer| ImageLayer::check_cache(layer));
 
         if all_layers_cached {
-            let mut all_layers: Vec<ImageLayer> = Vec::new();
-            for layer in layers {
-                let layer = ImageLayer::load(&layer).expect("Can't load cached layer");
-                all_layers.push(layer);
+            // A corrupt metadata entry is not fatal, it is rebuilt from the export below
+            match ImageMetadata::load(&image_id) {
+                Ok(metadata) => {
+                    let image = ImageRepr::from_metadata(name.clone(), temp_dir, &metadata, &HashMap::new());
+                    let unreadable: Vec<&ImageLayer> =
+                        image.layers.iter().filter(|layer| layer.error.is_some()).collect();
+                    if unreadable.is_empty() {
+                        return Ok(image);
+                    }
+                    // Corrupt entries are dropped, so they are parsed again from the export below
+                    for layer in unreadable {
+                        if let Err(e) = ImageLayer::remove_from_cache(&layer.name) {
+                            eprintln!("Can't remove cached layer {}: {}", layer.name, e.describe());
+                        }
+                    }
+                    temp_dir = image.temp_dir;
+                }
+                Err(e) => eprintln!("Can't load image metadata: {}", e.describe()),
             }
-            return Ok(ImageRepr {
-                name,
-                temp_dir,
-                layers: all_layers,
-            });
         }
 
-        println!("Missing some layers in the cache, need to redownload the image");
+        eprintln!("Missing some layers in the cache, need to redownload the image");
 
-        download_image_file(&docker, &name, &img_tar_file_path).await?;
+        let img_folder = ImageRepr::export(docker, &name).await?;
+        let manifest_file = get_manifest_config_file(&img_folder)?;
+        let metadata = ImageMetadata::from_export(&img_folder, &manifest_file, &image_id)?;
 
-        // Untar image
-        let file: File = File::open(&img_tar_file_path).expect("Can't open file");
-        let mut archive = Archive::new(file);
-        archive.unpack(&img_folder).expect("Can't unpack image");
+        // split layers into cached and non-cached
+        let mut non_cached_layers = layers.clone();
+        ImageLayer::filter_cached_layers(&mut non_cached_layers);
+        let non_cached_blobs: Vec<LayerBlob> = get_layer_blobs(&img_folder, &metadata)?
+            .into_iter()
+            .filter(|blob| non_cached_layers.contains(&blob.diff_id))
+            .collect();
 
-        println!("Finished downloading the image, unpacking layers");
+        // get non-cached layer trees; layers that fail to parse are reported but do not stop
+        // the rest of the image from loading
+        let parsed_layers: HashMap<String, Result<FileTree, ImageParcingError>> =
+            parse_image_layers(&non_cached_blobs).into_iter().collect();
 
-        let layer_folder = img_folder.join("blobs").join("sha256");
+        // Construct cache from non-cached layers
+        for (layer_name, layer_tree) in parsed_layers.iter() {
+            if let Ok(layer_tree) = layer_tree {
+                let layer = ImageLayer::new(layer_name.clone(), layer_tree.clone(), String::new(), None);
+                if let Err(e) = layer.save() {
+                    eprintln!("Can't cache layer {}: {}", layer_name, e.describe());
+                }
+            }
+        }
 
-        // split layers into cached and non-cached
-        let mut non_cached_layers = layers.clone();
+        if parsed_layers.values().all(|tree| tree.is_ok()) {
+            if let Err(e) = metadata.save() {
+                eprintln!("Can't cache image metadata: {}", e.describe());
+            }
+        }
 
-        // Get cached layers
-        let _ = ImageLayer::filter_cached_layers(&mut non_cached_layers);
+        Ok(ImageRepr::from_metadata(
+            name,
+            temp_dir,
+            &metadata,
+            &parsed_layers,
+        ))
+    }
 
-        // get non-cached layer trees
-        let non_cached_layer_trees: Vec<(String, FileTree)> =
-            unpack_image_layers(&layer_folder, &non_cached_layers)?;
+    /// Exports the image from docker and unpacks it to the image cache directory.
+    /// Returns the path to the unpacked image.
+    async fn export(docker: &Docker, name: &str) -> Result<PathBuf, ImageParcingError> {
+        cleanup::register_staging_dir(&ImageRepr::get_img_cache_dir(name)?);
+        let img_tar_file_path = ImageRepr::get_img_cache_dir(name)?.join("image.tar");
+        let img_folder = ImageRepr::get_img_cache_dir(name)?.join("image");
 
-        let manifest_file = get_manifest_config_file(&img_folder)?;
-        let commands = get_layer_commands(&img_folder, &manifest_file)?;
-        let cmd_map: HashMap<String, String> = layers
-            .iter()
-            .map(|layer| layer.to_string())
-            .zip(commands.into_iter())
-            .collect();
+        download_image_file(docker, name, &img_tar_file_path).await?;
 
-        // Construct cache from non-cached layers
-        for (layer_name, layer_tree) in non_cached_layer_trees.into_iter() {
-            let command = match cmd_map.get(&layer_name) {
-                Some(cmd) => cmd.clone(),
-                None => {
-                    println!("No command for layer {}", layer_name);
-                    String::from("Not available")
-                }
-            };
+        // Untar image
+        let file: File = File::open(&img_tar_file_path).with_path(&img_tar_file_path)?;
+        let mut archive = Archive::new(file);
+        archive.unpack(&img_folder).with_path(&img_tar_file_path)?;
+
+        eprintln!("Finished downloading the image, unpacking layers");
+        Ok(img_folder)
+    }
 
-            let layer = ImageLayer::new(layer_name, layer_tree, command);
-            layer.save()?;
+    /// Re-checks the cached layers of a local image: every cached layer's blob is hashed and
+    /// compared against its diff_id, and its tree is rebuilt and compared against the cached one.
+    /// Entries that do not match are removed from the cache so they are rebuilt on next open.
+    pub async fn verify_cache(
+        name: &str,
+        docker: &Docker,
+    ) -> Result<Vec<CacheEntryReport>, ImageParcingError> {
+        let (image_id, _) = get_image_id_and_layers(docker, name).await?;
+        let img_folder = ImageRepr::export(docker, name).await?;
+        let manifest_file = get_manifest_config_file(&img_folder)?;
+        let metadata = ImageMetadata::from_export(&img_folder, &manifest_file, &image_id)?;
+
+        let mut reports: Vec<CacheEntryReport> = Vec::new();
+        for blob in get_layer_blobs(&img_folder, &metadata)? {
+            if !ImageLayer::check_cache(&blob.diff_id) {
+                reports.push(CacheEntryReport {
+                    diff_id: blob.diff_id,
+                    status: CacheEntryStatus::NotCached,
+                });
+                continue;
+            }
+
+            eprintln!("Verifying layer: {}", blob.diff_id);
+            let status = verify_cached_layer(&blob);
+            // An unreadable blob says nothing about the cached tree, so it is kept
+            if matches!(status, CacheEntryStatus::DigestMismatch(_) | CacheEntryStatus::TreeMismatch) {
+                ImageLayer::remove_from_cache(&blob.diff_id)?;
+            }
+            reports.push(CacheEntryReport {
+                diff_id: blob.diff_id,
+                status,
+            });
         }
 
-        // Finally, load all layers from cache
+        Ok(reports)
+    }
+
+    /// Assembles the image from the layers that were just parsed and the cache.
+    /// Layers that failed to parse or to load are replaced by error placeholders.
+    fn from_metadata(
+        name: String,
+        temp_dir: TempDir,
+        metadata: &ImageMetadata,
+        parsed_layers: &HashMap<String, Result<FileTree, ImageParcingError>>,
+    ) -> ImageRepr {
         let mut all_layers: Vec<ImageLayer> = Vec::new();
-        for layer in layers {
-            let layer = ImageLayer::load(&layer).expect("Can't load layer");
+        for layer in metadata.layers.iter() {
+            let layer = match parsed_layers.get(&layer.diff_id) {
+                Some(Ok(tree)) => ImageLayer::new(
+                    layer.diff_id.clone(),
+                    tree.clone(),
+                    layer.command.clone(),
+                    layer.created.clone(),
+                ),
+                Some(Err(e)) => ImageLayer::failed(layer, e),
+                None => match ImageLayer::load(layer) {
+                    Ok(loaded) => loaded,
+                    Err(e) => ImageLayer::failed(layer, &e),
+                },
+            };
             all_layers.push(layer);
         }
 
-        Ok(ImageRepr {
+        // What counts as modified depends on the layers below, so it is worked out per image
+        // rather than stored in the content-addressed layer cache
+        let mut merged = FileTree::empty();
+        for layer in all_layers.iter_mut() {
+            layer.tree = layer.tree.classify_against(&merged);
+            merged.apply_layer(&layer.tree);
+        }
+
+        ImageRepr {
             name,
             temp_dir,
             layers: all_layers,
-        })
+        }
+    }
+
+    /// Where each file content occurs in the image: layer index and path for every content hash.
+    /// Indexes of different images can be compared by hash as well.
+    pub fn content_index(&self) -> HashMap<String, Vec<(usize, PathBuf)>> {
+        let mut index: HashMap<String, Vec<(usize, PathBuf)>> = HashMap::new();
+        for (i, layer) in self.layers.iter().enumerate() {
+            for (hash, paths) in layer.tree.files_by_content() {
+                index
+                    .entry(hash)
+                    .or_default()
+                    .extend(paths.into_iter().map(|path| (i, path)));
+            }
+        }
+        index
+    }
+
+    /// The filesystem as seen after applying layers `0..=layer` in order
+    pub fn aggregated_tree(&self, layer: usize) -> FileTree {
+        let trees: Vec<&FileTree> = self
+            .layers
+            .iter()
+            .take(layer + 1)
+            .map(|layer| &layer.tree)
+            .collect();
+        FileTree::merge(&trees)
+    }
+
+    /// The filesystem a container of the image starts with
+    pub fn final_tree(&self) -> FileTree {
+        self.aggregated_tree(self.layers.len().saturating_sub(1))
     }
 
     pub fn get_img_cache_dir(image: &str) -> Result<PathBuf, ImageParcingError> {
@@ -228,7 +550,7 @@ impl ImageRepr {
         let home = home_dir().ok_or(ImageParcingError::CantGetAHomeDir)?;
         let cache_path = home.join(format!(".cache/freightview/image_cache/{}", image));
         if !cache_path.exists() {
-            std::fs::create_dir_all(&cache_path)?;
+            std::fs::create_dir_all(&cache_path).with_path(&cache_path)?;
         }
         Ok(cache_path)
     }
@@ -236,8 +558,9 @@ impl ImageRepr {
     pub fn clean_up_img_cache(name: &str) -> Result<(), ImageParcingError> {
         let cache_path = ImageRepr::get_img_cache_dir(name)?;
         if cache_path.exists() {
-            std::fs::remove_dir_all(&cache_path)?;
+            std::fs::remove_dir_all(&cache_path).with_path(&cache_path)?;
         }
+        cleanup::unregister_staging_dir(&cache_path);
         Ok(())
     }
 }
@@ -263,27 +586,40 @@ pub async fn get_image_summary(docker: &Docker, img_name: &String) -> Option<Ima
     image
 }
 
-pub async fn get_image_layers(
+/// Returns the id of the image together with its layer diff_ids
+pub async fn get_image_id_and_layers(
     docker: &Docker,
-    img_name: &String,
-) -> Result<Vec<String>, ImageParcingError> {
-    let image_details = docker.inspect_image(img_name).await?;
+    img_name: &str,
+) -> Result<(String, Vec<String>), ImageParcingError> {
+    let missing = |reason: &str| ImageParcingError::DockerAPIError {
+        image: img_name.to_string(),
+        reason: reason.to_string(),
+        source: None,
+    };
+    let image_details = docker.inspect_image(img_name).await.map_err(|source| {
+        ImageParcingError::DockerAPIError {
+            image: img_name.to_string(),
+            reason: String::from("can't inspect image"),
+            source: Some(source),
+        }
+    })?;
+    let image_id = image_details.id.ok_or_else(|| missing("image has no id"))?;
     let rfs = image_details
         .root_fs
-        .ok_or(ImageParcingError::DockerAPIError)?;
-    let layers = rfs.layers.ok_or(ImageParcingError::DockerAPIError)?;
-    Ok(layers)
+        .ok_or_else(|| missing("image has no root filesystem"))?;
+    let layers = rfs.layers.ok_or_else(|| missing("image has no layers"))?;
+    Ok((image_id, layers))
 }
 
 pub async fn download_image_file(
     docker: &Docker,
-    img_name: &String,
-    img_tar_file_path: &PathBuf,
+    img_name: &str,
+    img_tar_file_path: &Path,
 ) -> Result<(), ImageParcingError> {
     let mut stream = docker.export_image(img_name);
     let mut context = std::task::Context::from_waker(futures_util::task::noop_waker_ref());
-    println!("Downloading image: {}", img_name);
-    let mut file = File::create(&img_tar_file_path)?;
+    eprintln!("Downloading image: {}", img_name);
+    let mut file = File::create(img_tar_file_path).with_path(img_tar_file_path)?;
 
     loop {
         let poll_res = stream.poll_next_unpin(&mut context);
@@ -291,16 +627,12 @@ pub async fn download_image_file(
             Poll::Ready(option) => match option {
                 // Got a chunk of data
                 Some(errchnk) => {
-                    let res = file.write_all(&errchnk?);
-                    if let Err(e) = res {
-                        println!("Error writing to file: {}", e);
-                        return Err(ImageParcingError::FilesystemError);
-                    }
-                    let res = file.flush();
-                    if let Err(e) = res {
-                        println!("Error flushing file: {}", e);
-                        return Err(ImageParcingError::FilesystemError);
-                    }
+                    let chunk = errchnk.map_err(|source| ImageParcingError::CantDownloadImage {
+                        image: img_name.to_string(),
+                        source,
+                    })?;
+                    file.write_all(&chunk).with_path(img_tar_file_path)?;
+                    file.flush().with_path(img_tar_file_path)?;
                 }
                 // This is the end of the stream
                 None => {
@@ -314,67 +646,154 @@ pub async fn download_image_file(
     }
 }
 
-pub fn unpack_image_layers(
-    layer_folder: &PathBuf,
-    layers: &Vec<String>,
-) -> Result<Vec<(String, FileTree)>, ImageParcingError> {
-    let mut layer_trees: Vec<(String, FileTree)> = Vec::new();
-
-    for layer in layers {
-        let layer_spec = &layer[7..];
-        let layer_tar_path = layer_folder.join(layer_spec);
-        let layer_dir_name = String::from(layer) + ".dir";
-        let layer_dir = layer_folder.join(&layer_dir_name);
+/// A layer blob in the exported image together with the diff_id it is expected to have
+pub struct LayerBlob {
+    pub diff_id: String,
+    pub blob_path: PathBuf,
+}
 
-        let layer_tar = File::open(&layer_tar_path).expect("Can't open layer");
+/// Matches the diff_ids of the image with the layer blobs of the export.
+/// Layers in the manifest are listed in the same order as diff_ids in the config.
+pub fn get_layer_blobs(
+    docker_root_folder: &Path,
+    metadata: &ImageMetadata,
+) -> Result<Vec<LayerBlob>, ImageParcingError> {
+    let manifest = read_manifest(docker_root_folder)?;
+    if manifest.Layers.len() != metadata.layers.len() {
+        return Err(ImageParcingError::LayerParsingError {
+            path: docker_root_folder.join("manifest.json"),
+            reason: format!(
+                "manifest lists {} layers, but config has {} diff_ids",
+                manifest.Layers.len(),
+                metadata.layers.len()
+            ),
+        });
+    }
 
-        println!("Unpacking layer: {} to {}", layer, layer_dir.display());
-        let mut layer_archive = Archive::new(layer_tar);
-        layer_archive.unpack(&layer_dir)?;
+    let blobs = metadata
+        .layers
+        .iter()
+        .zip(manifest.Layers.iter())
+        .map(|(layer, blob)| LayerBlob {
+            diff_id: layer.diff_id.clone(),
+            blob_path: docker_root_folder.join(blob),
+        })
+        .collect();
+    Ok(blobs)
+}
 
-        let main_dir = layer_dir.clone();
-        let layer_tree = FileTree::new(&main_dir)?;
-        layer_trees.push((layer.to_string(), layer_tree));
+/// Checks the cached tree of the layer against its blob
+fn verify_cached_layer(blob: &LayerBlob) -> CacheEntryStatus {
+    match parse_layer(&blob.blob_path) {
+        Err(e) => CacheEntryStatus::Unreadable(e.describe()),
+        Ok((diff_id, _)) if diff_id != blob.diff_id => CacheEntryStatus::DigestMismatch(diff_id),
+        Ok((_, tree)) if ImageLayer::load_tree(&blob.diff_id).ok().as_ref() != Some(&tree) => {
+            CacheEntryStatus::TreeMismatch
+        }
+        Ok(_) => CacheEntryStatus::Valid,
     }
+}
 
-    Ok(layer_trees)
+/// Builds the tree of a single layer blob straight from its tar stream.
+/// Returns the diff_id computed from the uncompressed tar stream along with the tree.
+pub fn parse_layer(blob_path: &Path) -> Result<(String, FileTree), ImageParcingError> {
+    let layer_tar = HashingReader::new(open_layer_blob(blob_path)?);
+    let mut layer_archive = Archive::new(layer_tar);
+    let layer_tree = FileTree::from_archive(&mut layer_archive, blob_path)?;
+    let diff_id = layer_archive.into_inner().finalize().with_path(blob_path)?;
+    Ok((diff_id, layer_tree))
 }
 
-pub fn get_manifest_config_file(docker_root_folder: &PathBuf) -> Result<String, ImageParcingError> {
-    let manifest_path = docker_root_folder.join("manifest.json");
-    let manifest_file = File::open(&manifest_path)?;
-    let manifest: Vec<Manifest> = serde_json::from_reader(manifest_file)?;
-    if manifest.len() != 1 {
-        return Err(ImageParcingError::LayerParsingError);
+/// Parses a layer blob, refusing it if its content does not hash to the diff_id it would be
+/// cached under
+fn parse_and_check_layer(layer: &LayerBlob) -> Result<FileTree, ImageParcingError> {
+    let (diff_id, tree) = parse_layer(&layer.blob_path)?;
+    if diff_id != layer.diff_id {
+        return Err(ImageParcingError::LayerDigestMismatch {
+            layer: layer.diff_id.clone(),
+            actual: diff_id,
+        });
     }
-    let config_file = &manifest[0].Config;
-    Ok(config_file.clone())
+    Ok(tree)
 }
 
-pub fn get_layer_commands(
-    docker_root_folder: &PathBuf,
-    config_file: &str,
-) -> Result<Vec<String>, ImageParcingError> {
-    let config_path = docker_root_folder.join(config_file);
-    let config_file = File::open(&config_path)?;
-    let config: Config = serde_json::from_reader(config_file)?;
-    let mut commands: Vec<String> = Vec::new();
-    for history in config.history {
-        match history.empty_layer {
-            Some(empty) => {
-                if empty {
-                    continue;
-                } else {
-                    commands.push(history.created_by.unwrap_or(String::from("Unknown command")).trim().to_string());
+/// Parses the layers and builds their trees. Every layer gets its own result, so that one
+/// bad layer, even one that makes the parser panic, does not prevent the others from being shown.
+pub fn parse_image_layers(layers: &[LayerBlob]) -> Vec<(String, Result<FileTree, ImageParcingError>)> {
+    // Layers are independent of each other, so they are parsed in parallel; the workers take
+    // them one by one so that no more tar streams are open than there are cores
+    let n_workers = std::thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(layers.len());
+    let next_layer = AtomicUsize::new(0);
+    let thread_panicked = |layer: &LayerBlob, message: String| ImageParcingError::LayerParsingError {
+        path: layer.blob_path.clone(),
+        reason: format!("parser panicked: {}", message),
+    };
+
+    let mut results: Vec<Option<Result<FileTree, ImageParcingError>>> = layers.iter().map(|_| None).collect();
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..n_workers)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut parsed = Vec::new();
+                    loop {
+                        let index = next_layer.fetch_add(1, Ordering::Relaxed);
+                        let Some(layer) = layers.get(index) else {
+                            break;
+                        };
+                        eprintln!("Parsing layer: {}", layer.diff_id);
+                        let result = cleanup::catch_panic(|| parse_and_check_layer(layer))
+                            .unwrap_or_else(|message| Err(thread_panicked(layer, message)));
+                        parsed.push((index, result));
+                    }
+                    parsed
+                })
+            })
+            .collect();
+        for worker in workers {
+            // Panics are caught per layer, so this only loses the results of a worker that
+            // died outside of parsing; its layers are reported below
+            if let Ok(parsed) = worker.join() {
+                for (index, result) in parsed {
+                    results[index] = Some(result);
                 }
             }
-            None => {
-                commands.push(history.created_by.unwrap_or(String::from("Unknown command")).trim().to_string());
-            }
         }
+    });
+
+    layers
+        .iter()
+        .zip(results)
+        .map(|(layer, result)| {
+            let result = result.unwrap_or_else(|| Err(thread_panicked(layer, String::from("worker thread died"))));
+            (layer.diff_id.clone(), result)
+        })
+        .collect()
+}
+
+fn read_manifest(docker_root_folder: &Path) -> Result<Manifest, ImageParcingError> {
+    let manifest_path = docker_root_folder.join("manifest.json");
+    let manifest_file = File::open(&manifest_path).with_path(&manifest_path)?;
+    let manifest: Vec<Manifest> = serde_json::from_reader(manifest_file).map_err(|source| {
+        ImageParcingError::ExportJSONError {
+            path: manifest_path.clone(),
+            source,
+        }
+    })?;
+    let n_images = manifest.len();
+    match manifest.into_iter().next() {
+        Some(manifest) if n_images == 1 => Ok(manifest),
+        _ => Err(ImageParcingError::LayerParsingError {
+            path: manifest_path,
+            reason: format!("expected one image in the manifest, found {}", n_images),
+        }),
     }
+}
 
-    Ok(commands)
+pub fn get_manifest_config_file(docker_root_folder: &Path) -> Result<String, ImageParcingError> {
+    let manifest = read_manifest(docker_root_folder)?;
+    Ok(manifest.Config)
 }
 
 #[allow(dead_code, non_snake_case)]
@@ -397,12 +816,17 @@ struct LayerSource {
 #[derive(Debug, Deserialize)]
 struct Config {
     history: Vec<History>,
+    rootfs: RootFs,
+}
+
+#[derive(Debug, Deserialize)]
+struct RootFs {
+    diff_ids: Vec<String>,
 }
 
-#[allow(dead_code)]
 #[derive(Debug, Deserialize)]
 struct History {
-    created: String,
+    created: Option<String>,
     created_by: Option<String>,
     empty_layer: Option<bool>,
 }
@@ -410,6 +834,7 @@ struct History {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use assert_matches::assert_matches;
 
     const DOCKER_FOLDER_PATH: &str = "test-assets/test-docker-tar/";
 
@@ -436,8 +861,18 @@ mod tests {
     fn read_config_file() {
         let docker_root_folder = PathBuf::from(DOCKER_FOLDER_PATH);
         let config_file = get_manifest_config_file(&docker_root_folder).unwrap();
-        let commands = get_layer_commands(&docker_root_folder, &config_file).unwrap();
+        let metadata =
+            ImageMetadata::from_export(&docker_root_folder, &config_file, "test-img").unwrap();
+        let commands: Vec<&str> = metadata
+            .layers
+            .iter()
+            .map(|layer| layer.command.as_str())
+            .collect();
         print!("{:?}", commands);
+        assert_eq!(
+            metadata.config_digest,
+            "sha256:0d99781172fa4757fb472183792b0d6e1df6d180d6361ea0ae5872ee4adc1f1c"
+        );
         assert_eq!(commands.len(), 5);
         assert_eq!(
             commands[0],
@@ -460,4 +895,109 @@ mod tests {
             "RUN /bin/sh -c rm /home/georgii/hello.txt # buildkit"
         );
     }
+
+    #[test]
+    fn metadata_aligns_layers_with_history() {
+        let docker_root_folder = PathBuf::from(DOCKER_FOLDER_PATH);
+        let config_file = get_manifest_config_file(&docker_root_folder).unwrap();
+        let metadata =
+            ImageMetadata::from_export(&docker_root_folder, &config_file, "test-img").unwrap();
+        assert_eq!(
+            metadata.layers[0].diff_id,
+            "sha256:270a1170e7e398434ff1b31e17e233f7d7b71aa99a40473615860068e86720af"
+        );
+        assert_eq!(
+            metadata.layers[0].created.as_deref(),
+            Some("2025-01-26T05:31:10.757640652Z")
+        );
+        assert_eq!(
+            metadata.layers[4].diff_id,
+            "sha256:e4862e938301df7ab3eb13568c9567991ebacea2de288507814164634668210d"
+        );
+        assert_eq!(
+            metadata.layers[4].created.as_deref(),
+            Some("2025-03-26T16:19:49.51815631+01:00")
+        );
+    }
+
+    #[test]
+    fn partially_loaded_image() {
+        let docker_root_folder = PathBuf::from(DOCKER_FOLDER_PATH);
+        let config_file = get_manifest_config_file(&docker_root_folder).unwrap();
+        let metadata =
+            ImageMetadata::from_export(&docker_root_folder, &config_file, "test-img").unwrap();
+
+        let mut parsed_layers: HashMap<String, Result<FileTree, ImageParcingError>> = metadata
+            .layers
+            .iter()
+            .map(|layer| (layer.diff_id.clone(), Ok(FileTree::empty())))
+            .collect();
+        let broken_layer = metadata.layers[2].diff_id.clone();
+        parsed_layers.insert(
+            broken_layer.clone(),
+            Err(ImageParcingError::LayerDigestMismatch {
+                layer: broken_layer.clone(),
+                actual: String::from("sha256:0000"),
+            }),
+        );
+
+        let img = ImageRepr::from_metadata(
+            String::from("test-img:latest"),
+            TempDir::new().unwrap(),
+            &metadata,
+            &parsed_layers,
+        );
+        assert_eq!(img.layers.len(), 5);
+        for (i, layer) in img.layers.iter().enumerate() {
+            assert_eq!(layer.command, metadata.layers[i].command);
+            if i == 2 {
+                assert_eq!(
+                    layer.error.as_deref(),
+                    Some(format!("layer {} hashes to sha256:0000", broken_layer).as_str())
+                );
+            } else {
+                assert_matches!(layer.error, None);
+            }
+        }
+    }
+
+    #[test]
+    fn every_layer_gets_a_result() {
+        let docker_root_folder = PathBuf::from(DOCKER_FOLDER_PATH);
+        let config_file = get_manifest_config_file(&docker_root_folder).unwrap();
+        let metadata =
+            ImageMetadata::from_export(&docker_root_folder, &config_file, "test-img").unwrap();
+        // The layer blobs are not part of the test export, so none of them can be parsed
+        let blobs = get_layer_blobs(&docker_root_folder, &metadata).unwrap();
+
+        let parsed = parse_image_layers(&blobs);
+        assert_eq!(parsed.len(), blobs.len());
+        for ((diff_id, tree), blob) in parsed.iter().zip(blobs.iter()) {
+            assert_eq!(diff_id, &blob.diff_id);
+            assert_matches!(tree, Err(ImageParcingError::FilesystemError { path, .. }) if path == &blob.blob_path);
+        }
+    }
+
+    #[test]
+    fn unreadable_blob_is_reported() {
+        let blob = LayerBlob {
+            diff_id: String::from("sha256:missing"),
+            blob_path: PathBuf::from(DOCKER_FOLDER_PATH).join("missing/layer.tar"),
+        };
+        assert_matches!(
+            verify_cached_layer(&blob),
+            CacheEntryStatus::Unreadable(reason) if reason.contains("missing/layer.tar")
+        );
+    }
+
+    #[test]
+    fn metadata_ser_deser() {
+        let docker_root_folder = PathBuf::from(DOCKER_FOLDER_PATH);
+        let config_file = get_manifest_config_file(&docker_root_folder).unwrap();
+        let metadata =
+            ImageMetadata::from_export(&docker_root_folder, &config_file, "test-img").unwrap();
+        let serialised = serde_json::to_string(&metadata).unwrap();
+        let deserialised: ImageMetadata = serde_json::from_str(&serialised).unwrap();
+        assert_eq!(metadata, deserialised);
+    }
 }
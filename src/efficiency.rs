@@ -0,0 +1,173 @@
+use crate::file_tree::{EntryOp, EntryType, FileTree};
+use crate::history::{Blame, touches};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// A path whose content was written by some layers and thrown away by later ones
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WastedPath {
+    pub path: PathBuf,
+    /// Bytes of all the versions that are not in the final image
+    pub wasted: u64,
+    /// Layers that wrote those versions
+    pub written_by: Vec<usize>,
+    /// Layer that removed or replaced the last of them
+    pub dropped_by: Option<usize>,
+}
+
+/// The same content stored by more than one layer
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DuplicateContent {
+    pub content_hash: String,
+    pub size: u64,
+    /// Layer index and path of every copy
+    pub places: Vec<(usize, PathBuf)>,
+}
+
+impl DuplicateContent {
+    /// Bytes that could be saved if the content was stored once
+    pub fn wasted(&self) -> u64 {
+        self.size * (self.places.len() as u64 - 1)
+    }
+}
+
+/// How much of what the layers store ends up in the final filesystem, like dive's score
+pub struct Efficiency {
+    /// Bytes of the files of all layers
+    pub total_bytes: u64,
+    /// Bytes of files that were deleted or overwritten by later layers
+    pub wasted_bytes: u64,
+    /// Worst first
+    pub wasted_paths: Vec<WastedPath>,
+    /// Worst first
+    pub duplicates: Vec<DuplicateContent>,
+}
+
+impl Efficiency {
+    pub fn analyze(layers: &[&FileTree]) -> Efficiency {
+        // A version of a file makes it into the final image if its layer is the last to write it
+        let blame = Blame::new(layers);
+        let mut total_bytes = 0;
+        let mut wasted: HashMap<PathBuf, (u64, Vec<usize>)> = HashMap::new();
+        let mut by_content: HashMap<String, (u64, Vec<(usize, PathBuf)>)> = HashMap::new();
+
+        for (index, layer) in layers.iter().enumerate() {
+            for node in layer.iter().skip(1) {
+                if node.fop() == EntryOp::Remove || node.ftype() == EntryType::Directory {
+                    continue;
+                }
+                let path = node.path();
+                total_bytes += node.size();
+                if let Some(hash) = node.content_hash() {
+                    by_content
+                        .entry(hash.to_string())
+                        .or_insert_with(|| (node.size(), Vec::new()))
+                        .1
+                        .push((index, path.clone()));
+                }
+                if blame.owner(&path) != Some(index) {
+                    let (bytes, written_by) = wasted.entry(path).or_default();
+                    *bytes += node.size();
+                    written_by.push(index);
+                }
+            }
+        }
+
+        let wasted_bytes = wasted.values().map(|(bytes, _)| bytes).sum();
+        let mut wasted_paths: Vec<WastedPath> = wasted
+            .into_iter()
+            .map(|(path, (wasted, written_by))| {
+                let last_write = written_by.last().copied().unwrap_or_default();
+                // Writing the same file again drops the first copy too
+                let dropped_by = (last_write + 1..layers.len()).find(|&index| touches(layers[index], &path));
+                WastedPath {
+                    path,
+                    wasted,
+                    written_by,
+                    dropped_by,
+                }
+            })
+            .collect();
+        wasted_paths.sort_by(|a, b| b.wasted.cmp(&a.wasted).then_with(|| a.path.cmp(&b.path)));
+
+        // Empty files are the same content everywhere, that is not worth reporting
+        let mut duplicates: Vec<DuplicateContent> = by_content
+            .into_iter()
+            .filter(|(_, (size, places))| {
+                *size > 0 && places.iter().any(|(layer, _)| *layer != places[0].0)
+            })
+            .map(|(content_hash, (size, places))| DuplicateContent {
+                content_hash,
+                size,
+                places,
+            })
+            .collect();
+        duplicates.sort_by(|a, b| {
+            b.wasted()
+                .cmp(&a.wasted())
+                .then_with(|| a.content_hash.cmp(&b.content_hash))
+        });
+
+        Efficiency {
+            total_bytes,
+            wasted_bytes,
+            wasted_paths,
+            duplicates,
+        }
+    }
+
+    /// Share of the stored bytes that are in the final filesystem, from 0 to 1
+    pub fn score(&self) -> f64 {
+        if self.total_bytes == 0 {
+            return 1.0;
+        }
+        (self.total_bytes - self.wasted_bytes) as f64 / self.total_bytes as f64
+    }
+
+    /// Bytes that could be saved if duplicated content was stored once
+    pub fn duplicate_bytes(&self) -> u64 {
+        self.duplicates.iter().map(DuplicateContent::wasted).sum()
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::file_tree::test_layer;
+
+    #[test]
+    fn wasted_space() {
+        let base = test_layer(&[("etc/app.conf", "0123456789"), ("tmp/archive.tar", "01234567890123456789")]);
+        let update = test_layer(&[("etc/app.conf", "98765"), ("opt/copy.tar", "01234567890123456789")]);
+        let cleanup = test_layer(&[("tmp/.wh.archive.tar", "")]);
+
+        let efficiency = Efficiency::analyze(&[&base, &update, &cleanup]);
+        assert_eq!(efficiency.total_bytes, 10 + 20 + 5 + 20);
+        assert_eq!(efficiency.wasted_bytes, 10 + 20);
+        assert_eq!(
+            efficiency.wasted_paths,
+            [
+                WastedPath {
+                    path: PathBuf::from("/tmp/archive.tar"),
+                    wasted: 20,
+                    written_by: vec![0],
+                    dropped_by: Some(2),
+                },
+                WastedPath {
+                    path: PathBuf::from("/etc/app.conf"),
+                    wasted: 10,
+                    written_by: vec![0],
+                    dropped_by: Some(1),
+                },
+            ]
+        );
+        assert_eq!(efficiency.score(), 25.0 / 55.0);
+
+        assert_eq!(efficiency.duplicates.len(), 1);
+        assert_eq!(
+            efficiency.duplicates[0].places,
+            [(0, PathBuf::from("/tmp/archive.tar")), (1, PathBuf::from("/opt/copy.tar"))]
+        );
+        assert_eq!(efficiency.duplicate_bytes(), 20);
+    }
+}
@@ -1,21 +1,136 @@
 use bollard::errors::Error as DockerError;
+use std::path::{Path, PathBuf};
+
+/// Process exit codes, one per class of errors, so that scripts can branch on them.
+/// 1 is left for unclassified errors and 2 is used by clap for usage errors.
+pub mod exit_codes {
+    pub const DOCKER: u8 = 3;
+    pub const FILESYSTEM: u8 = 4;
+    pub const CORRUPT_CACHE: u8 = 5;
+    pub const IMAGE_FORMAT: u8 = 6;
+    pub const INTEGRITY: u8 = 7;
+    pub const CI_FAILED: u8 = 8;
+    pub const CI_CONFIG: u8 = 9;
+}
 
-#[allow(unused)]
 #[derive(Debug, thiserror::Error)]
 pub enum ImageParcingError {
-    CantDownloadImage,
+    #[error("can't connect to docker")]
+    CantConnectToDocker {
+        #[source]
+        source: DockerError,
+    },
+    #[error("can't export image {image} from docker")]
+    CantDownloadImage {
+        image: String,
+        #[source]
+        source: DockerError,
+    },
+    #[error("can't find the home directory to put the cache in")]
     CantGetAHomeDir,
-    FilesystemError,
-    JSONError,
-    DockerAPIError,
-    LayerParsingError,
-    NonUnixFileSystem,
-    UnparceableFileName,
+    #[error("filesystem error at {}", path.display())]
+    FilesystemError {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("can't read or write JSON at {}", path.display())]
+    JSONError {
+        path: PathBuf,
+        #[source]
+        source: serde_json::Error,
+    },
+    /// The manifest or config of an exported image, as opposed to a file of the cache
+    #[error("can't parse {} of the exported image", path.display())]
+    ExportJSONError {
+        path: PathBuf,
+        #[source]
+        source: serde_json::Error,
+    },
+    #[error("docker API error for image {image}: {reason}")]
+    DockerAPIError {
+        image: String,
+        reason: String,
+        #[source]
+        source: Option<DockerError>,
+    },
+    #[error("can't parse {}: {reason}", path.display())]
+    LayerParsingError { path: PathBuf, reason: String },
+    #[error("layer {layer} hashes to {actual}")]
+    LayerDigestMismatch { layer: String, actual: String },
+    #[error("{n_invalid} cached layer(s) of {image} failed verification")]
+    CacheVerificationFailed { image: String, n_invalid: usize },
+    #[error("invalid CI config {}", path.display())]
+    CiConfigError {
+        path: PathBuf,
+        #[source]
+        source: serde_norway::Error,
+    },
+    #[error("{n_failed} CI rule(s) failed for {image}")]
+    CiRulesFailed { image: String, n_failed: usize },
+}
+
+impl ImageParcingError {
+    /// One-line description of the error together with everything that caused it
+    pub fn describe(&self) -> String {
+        let mut description = self.to_string();
+        let mut source = std::error::Error::source(self);
+        while let Some(cause) = source {
+            description.push_str(": ");
+            description.push_str(&cause.to_string());
+            source = cause.source();
+        }
+        description
+    }
+
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            ImageParcingError::CantConnectToDocker { .. }
+            | ImageParcingError::CantDownloadImage { .. }
+            | ImageParcingError::DockerAPIError { .. } => exit_codes::DOCKER,
+            ImageParcingError::CantGetAHomeDir | ImageParcingError::FilesystemError { .. } => {
+                exit_codes::FILESYSTEM
+            }
+            ImageParcingError::JSONError { .. } => exit_codes::CORRUPT_CACHE,
+            ImageParcingError::LayerParsingError { .. } | ImageParcingError::ExportJSONError { .. } => {
+                exit_codes::IMAGE_FORMAT
+            }
+            ImageParcingError::LayerDigestMismatch { .. }
+            | ImageParcingError::CacheVerificationFailed { .. } => exit_codes::INTEGRITY,
+            ImageParcingError::CiRulesFailed { .. } => exit_codes::CI_FAILED,
+            ImageParcingError::CiConfigError { .. } => exit_codes::CI_CONFIG,
+        }
+    }
+}
+
+/// Attaches the path that was being worked on to io and JSON errors
+pub trait PathContext<T> {
+    fn with_path(self, path: &Path) -> Result<T, ImageParcingError>;
+}
+
+impl<T> PathContext<T> for Result<T, std::io::Error> {
+    fn with_path(self, path: &Path) -> Result<T, ImageParcingError> {
+        self.map_err(|source| ImageParcingError::FilesystemError {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+}
+
+impl<T> PathContext<T> for Result<T, serde_json::Error> {
+    fn with_path(self, path: &Path) -> Result<T, ImageParcingError> {
+        self.map_err(|source| ImageParcingError::JSONError {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
 pub enum GUIError {
     CantFilterTree,
+    /// The search string can't be compiled, with the reason
+    InvalidPattern(String),
 }
 
 impl std::fmt::Display for GUIError {
@@ -24,26 +139,37 @@ impl std::fmt::Display for GUIError {
     }
 }
 
-impl std::fmt::Display for ImageParcingError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
-    }
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
 
-impl From<std::io::Error> for ImageParcingError {
-    fn from(_err: std::io::Error) -> ImageParcingError {
-        ImageParcingError::FilesystemError
+    #[test]
+    fn filesystem_error_keeps_path_and_source() {
+        let err = std::fs::File::open("/definitely/not/here")
+            .with_path(Path::new("/definitely/not/here"))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "filesystem error at /definitely/not/here");
+        assert!(err.source().is_some());
+        assert!(err.describe().starts_with("filesystem error at /definitely/not/here: "));
+        assert_eq!(err.exit_code(), exit_codes::FILESYSTEM);
     }
-}
 
-impl From<serde_json::Error> for ImageParcingError {
-    fn from(_err: serde_json::Error) -> ImageParcingError {
-        ImageParcingError::JSONError
-    }
-}
+    #[test]
+    fn json_error_keeps_path_and_source() {
+        let err = serde_json::from_str::<Vec<u32>>("{")
+            .with_path(Path::new("sha256:abc.json"))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "can't read or write JSON at sha256:abc.json");
+        assert!(err.source().is_some());
+        assert_eq!(err.exit_code(), exit_codes::CORRUPT_CACHE);
 
-impl From<DockerError> for ImageParcingError {
-    fn from(_err: DockerError) -> ImageParcingError {
-        ImageParcingError::DockerAPIError
+        let source = serde_json::from_str::<Vec<u32>>("{").unwrap_err();
+        let err = ImageParcingError::ExportJSONError {
+            path: PathBuf::from("manifest.json"),
+            source,
+        };
+        assert_eq!(err.to_string(), "can't parse manifest.json of the exported image");
+        assert_eq!(err.exit_code(), exit_codes::IMAGE_FORMAT);
     }
 }
@@ -0,0 +1,116 @@
+use serde::de::{self, Deserializer, SeqAccess, Visitor};
+use serde::ser::Serializer;
+use std::ffi::{OsStr, OsString};
+use std::fmt::Write;
+
+/// Makes a file name or path printable: bytes that are not UTF-8 are shown as `\xNN`
+/// and control characters the way Rust escapes them, e.g. `\n`
+pub fn escape_name(name: &OsStr) -> String {
+    let mut escaped = String::new();
+    for chunk in name.as_encoded_bytes().utf8_chunks() {
+        for c in chunk.valid().chars() {
+            if c.is_control() {
+                escaped.extend(c.escape_debug());
+            } else {
+                escaped.push(c);
+            }
+        }
+        for byte in chunk.invalid() {
+            let _ = write!(escaped, "\\x{:02x}", byte);
+        }
+    }
+    escaped
+}
+
+#[cfg(unix)]
+fn os_string_from_bytes(bytes: Vec<u8>) -> Result<OsString, String> {
+    use std::os::unix::ffi::OsStringExt;
+    Ok(OsString::from_vec(bytes))
+}
+
+#[cfg(not(unix))]
+fn os_string_from_bytes(bytes: Vec<u8>) -> Result<OsString, String> {
+    String::from_utf8(bytes)
+        .map(OsString::from)
+        .map_err(|_| "file names that are not UTF-8 can only be read on unix".to_string())
+}
+
+/// Serialises file names and paths without losing bytes that are not UTF-8. Names that are
+/// valid UTF-8 are stored as strings, all others as arrays of bytes.
+/// Use with `#[serde(with = "crate::file_names::lossless")]`.
+pub mod lossless {
+    use super::*;
+
+    pub fn serialize<T: AsRef<OsStr>, S: Serializer>(name: &T, serializer: S) -> Result<S::Ok, S::Error> {
+        let name = name.as_ref();
+        match name.to_str() {
+            Some(name) => serializer.serialize_str(name),
+            None => serializer.serialize_bytes(name.as_encoded_bytes()),
+        }
+    }
+
+    pub fn deserialize<'de, T: From<OsString>, D: Deserializer<'de>>(deserializer: D) -> Result<T, D::Error> {
+        deserializer.deserialize_any(NameVisitor).map(T::from)
+    }
+
+    struct NameVisitor;
+
+    impl<'de> Visitor<'de> for NameVisitor {
+        type Value = OsString;
+
+        fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+            formatter.write_str("a file name as a string or an array of bytes")
+        }
+
+        fn visit_str<E: de::Error>(self, name: &str) -> Result<OsString, E> {
+            Ok(OsString::from(name))
+        }
+
+        fn visit_bytes<E: de::Error>(self, name: &[u8]) -> Result<OsString, E> {
+            os_string_from_bytes(name.to_vec()).map_err(E::custom)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<OsString, A::Error> {
+            let mut bytes: Vec<u8> = Vec::new();
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+            os_string_from_bytes(bytes).map_err(de::Error::custom)
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::PathBuf;
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Named {
+        #[serde(with = "lossless")]
+        name: OsString,
+        #[serde(with = "lossless")]
+        path: PathBuf,
+    }
+
+    #[test]
+    fn escaped_names() {
+        assert_eq!(escape_name(OsStr::new("passwd")), "passwd");
+        assert_eq!(escape_name(OsStr::new("naïve")), "naïve");
+        assert_eq!(escape_name(OsStr::from_bytes(b"caf\xe9")), "caf\\xe9");
+        assert_eq!(escape_name(OsStr::new("two\nlines")), "two\\nlines");
+    }
+
+    #[test]
+    fn names_survive_serialisation() {
+        let named = Named {
+            name: OsStr::from_bytes(b"caf\xe9").to_os_string(),
+            path: PathBuf::from("/etc/passwd"),
+        };
+        let json = serde_json::to_string(&named).unwrap();
+        assert_eq!(json, r#"{"name":[99,97,102,233],"path":"/etc/passwd"}"#);
+        assert_eq!(serde_json::from_str::<Named>(&json).unwrap(), named);
+    }
+}
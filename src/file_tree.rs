@@ -1,50 +1,122 @@
-use crate::exceptions::{GUIError, ImageParcingError};
+use crate::capabilities::{CAPABILITY_XATTR, describe_capabilities};
+use crate::exceptions::{GUIError, ImageParcingError, PathContext};
+use crate::file_names::escape_name;
+use crate::layer_digest::HashingReader;
 use serde::{Deserialize, Serialize};
-use std::cell::RefCell;
+use std::borrow::Cow;
 use std::collections::VecDeque;
 
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::io::Read;
+use std::ops::{Deref, Range};
 #[cfg(unix)]
-use std::os::unix::fs::PermissionsExt;
-use std::path::{Path, PathBuf};
-use std::rc::Rc;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Component, Path, PathBuf};
 
-use std::fs::FileType;
+use std::fs::Metadata;
+use tar::{Archive, Entry, EntryType as TarEntryType, Header};
 
 #[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Hash)]
 pub enum EntryType {
     Directory,
     File,
-    Symlink(PathBuf),
+    Symlink(#[serde(with = "crate::file_names::lossless")] PathBuf),
+    // Another name for a file of the same layer; the target is an absolute path in the layer
+    Hardlink(#[serde(with = "crate::file_names::lossless")] PathBuf),
+    CharDevice { major: u32, minor: u32 },
+    BlockDevice { major: u32, minor: u32 },
+    Fifo,
+    Socket,
     Badfile,
 }
 
+#[cfg(unix)]
 impl EntryType {
-    pub fn from_ftype(ftype: FileType, abspath: &Path) -> EntryType {
+    pub fn from_metadata(metadata: &Metadata, abspath: &Path) -> EntryType {
+        let ftype = metadata.file_type();
         if ftype.is_symlink() {
             let symlink_path = std::fs::read_link(abspath);
             match symlink_path {
                 Err(_) => {
-                    println!("Error reading symlink: {}", abspath.display());
-                    return EntryType::Badfile;
-                }
-                Ok(path) => {
-                    return EntryType::Symlink(path);
+                    eprintln!("Error reading symlink: {}", abspath.display());
+                    EntryType::Badfile
                 }
+                Ok(path) => EntryType::Symlink(path),
             }
         } else if ftype.is_file() {
             EntryType::File
         } else if ftype.is_dir() {
             EntryType::Directory
+        } else if ftype.is_char_device() {
+            let (major, minor) = split_device_id(metadata.rdev());
+            EntryType::CharDevice { major, minor }
+        } else if ftype.is_block_device() {
+            let (major, minor) = split_device_id(metadata.rdev());
+            EntryType::BlockDevice { major, minor }
+        } else if ftype.is_fifo() {
+            EntryType::Fifo
+        } else if ftype.is_socket() {
+            EntryType::Socket
         } else {
             EntryType::Badfile
         }
     }
 }
 
+impl EntryType {
+    /// Type of a tar entry; `None` for entries that do not describe a file, e.g. global headers
+    fn from_header(header: &Header, link_name: Option<PathBuf>) -> Option<EntryType> {
+        let device = || {
+            (
+                header.device_major().ok().flatten().unwrap_or(0),
+                header.device_minor().ok().flatten().unwrap_or(0),
+            )
+        };
+        let link_name = || link_name.clone().unwrap_or_default();
+        let ftype = match header.entry_type() {
+            TarEntryType::Regular | TarEntryType::Continuous | TarEntryType::GNUSparse => {
+                EntryType::File
+            }
+            TarEntryType::Directory => EntryType::Directory,
+            TarEntryType::Symlink => EntryType::Symlink(link_name()),
+            // Targets are named like entry paths, e.g. `./usr/bin/x`
+            TarEntryType::Link => match normalize_archive_path(&link_name()) {
+                Some(target) => EntryType::Hardlink(target),
+                None => EntryType::Badfile,
+            },
+            TarEntryType::Char => {
+                let (major, minor) = device();
+                EntryType::CharDevice { major, minor }
+            }
+            TarEntryType::Block => {
+                let (major, minor) = device();
+                EntryType::BlockDevice { major, minor }
+            }
+            TarEntryType::Fifo => EntryType::Fifo,
+            TarEntryType::XGlobalHeader | TarEntryType::XHeader => return None,
+            TarEntryType::GNULongName | TarEntryType::GNULongLink => return None,
+            _ => EntryType::Badfile,
+        };
+        Some(ftype)
+    }
+}
+
+/// Splits a linux device id into its major and minor numbers
+#[cfg(unix)]
+fn split_device_id(rdev: u64) -> (u32, u32) {
+    let major = ((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff);
+    let minor = (rdev & 0xff) | ((rdev >> 12) & !0xff);
+    (major as u32, minor as u32)
+}
+
 // File operations type
 #[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Hash)]
 pub enum EntryOp {
     Add,
+    // Overwrites an entry of the layers below; only known once the layer is compared
+    // against them, see FileTree::classify_against
+    Modify,
     Remove,
 }
 
@@ -52,147 +124,413 @@ impl std::fmt::Display for EntryOp {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             EntryOp::Add => write!(f, "Add"),
+            EntryOp::Modify => write!(f, "Modify"),
             EntryOp::Remove => write!(f, "Remove"),
         }
     }
 }
 
+/// Number of entries of a layer per operation
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct OpCounts {
+    pub added: usize,
+    pub modified: usize,
+    pub removed: usize,
+}
+
+/// How an entry differs from what was at its path before
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum PathChange {
+    Added,
+    /// The content or the type changed
+    Modified,
+    /// Only the mode, the owner or the extended attributes changed, e.g. after a `chmod`
+    Metadata,
+    /// Removed by a whiteout of the path, of a directory above it or by an opaque directory
+    Removed,
+}
+
+impl std::fmt::Display for PathChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PathChange::Added => write!(f, "Add"),
+            PathChange::Modified => write!(f, "Modify"),
+            PathChange::Metadata => write!(f, "Metadata"),
+            PathChange::Removed => write!(f, "Remove"),
+        }
+    }
+}
+
+/// Index of a node in its FileTree
+pub type NodeId = usize;
+
 #[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Debug)]
 pub struct FileTreeNode {
-    // Children might need to be modified after creation, this is why we use RefCell
-    // Multiple reference might be needed when e.g going through the tree breadth or depth first
-    // Hence we use Rc<RefCell<T>> to allow multiple ownership and mutability
-    children: RefCell<Vec<Rc<RefCell<FileTreeNode>>>>,
     data: FileTreeNodeData,
+    parent: Option<NodeId>,
+    // The children are the nodes in this range of the tree
+    children: Range<NodeId>,
 }
 
 #[derive(Serialize, Deserialize, Eq, PartialEq, Hash, Clone, Debug)]
 pub struct FileTreeNodeData {
-    name: String,
+    // Names are kept byte for byte, they don't have to be UTF-8
+    #[serde(with = "crate::file_names::lossless")]
+    name: OsString,
     ftype: EntryType,
     fop: EntryOp,
     permissions: String,
-    // This is a path of the extracted docker tarball; It contains the pathes with .wh. inside
+    // Permission bits including setuid, setgid and sticky, without the file type
+    #[serde(default)]
+    mode: u32,
+    #[serde(default)]
+    uid: u64,
+    #[serde(default)]
+    gid: u64,
+    // Modification time, seconds since the epoch
+    #[serde(default)]
+    mtime: u64,
+    // This is a path inside the layer tarball; It contains the pathes with .wh. inside
+    #[serde(with = "crate::file_names::lossless")]
     disk_rel_path: PathBuf,
     // This is a path that is visible to the user; The .wh. files are stripped
+    #[serde(with = "crate::file_names::lossless")]
     vis_rel_path: PathBuf,
     size: u64,
+    // sha256 of the content of regular files, in the `sha256:<hex>` form
+    #[serde(default)]
+    content_hash: Option<String>,
+    // Extended attributes, e.g. security.capability
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    xattrs: BTreeMap<String, Vec<u8>>,
+    // Directory marked with an opaque whiteout: it hides everything below it in lower layers
+    #[serde(default)]
+    opaque: bool,
 }
 
-fn parse_name(name: &str) -> (String, EntryOp) {
-    let stripped_name = name.strip_prefix(".wh.");
+/// Marker file of an opaque directory, as defined by the OCI image spec
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+fn parse_name(name: &OsStr) -> (OsString, EntryOp) {
+    let stripped_name = name.as_encoded_bytes().strip_prefix(b".wh.");
     match stripped_name {
-        Some(stripped_name) => (stripped_name.to_string(), EntryOp::Remove),
-        None => (name.to_string(), EntryOp::Add),
+        // SAFETY: the bytes come from an OsStr and are split right after an ASCII character
+        Some(stripped_name) => (
+            unsafe { OsStr::from_encoded_bytes_unchecked(stripped_name) }.to_os_string(),
+            EntryOp::Remove,
+        ),
+        None => (name.to_os_string(), EntryOp::Add),
     }
 }
 
 #[cfg(unix)]
+fn node_data_from_path(relpath: &Path, abspath: &Path) -> Result<FileTreeNodeData, ImageParcingError> {
+    let metadata = abspath.symlink_metadata().with_path(abspath)?;
+    let ftype = EntryType::from_metadata(&metadata, abspath);
+    let mode = metadata.mode() & MODE_BITS;
+    let permissions = perm_str_from_u32(mode);
+    let size = metadata.len();
+    let content_hash = match ftype {
+        EntryType::File => {
+            let file = std::fs::File::open(abspath).with_path(abspath)?;
+            Some(HashingReader::new(file).finalize().with_path(abspath)?)
+        }
+        _ => None,
+    };
+
+    let name_to_parse = relpath.file_name();
+    let parent_dir = relpath.parent();
+    let name = name_to_parse.unwrap_or(OsStr::new("/"));
+
+    let (name, fop) = parse_name(name);
+    let vis_rel_path = match parent_dir {
+        Some(parent_dir) => {
+            let mut vis_rel_path = parent_dir.to_path_buf();
+            vis_rel_path.push(&name);
+            vis_rel_path
+        }
+        None => PathBuf::from("/"),
+    };
+
+    Ok(FileTreeNodeData {
+        name,
+        ftype,
+        fop,
+        permissions,
+        mode,
+        uid: metadata.uid() as u64,
+        gid: metadata.gid() as u64,
+        mtime: metadata.mtime().max(0) as u64,
+        disk_rel_path: relpath.to_path_buf(),
+        vis_rel_path,
+        size,
+        content_hash,
+        xattrs: BTreeMap::new(),
+        opaque: false,
+    })
+}
+
+/// Data of a directory that is only known from the paths below it
+fn implicit_dir_data(name: OsString, fop: EntryOp, disk_rel_path: PathBuf, vis_rel_path: PathBuf) -> FileTreeNodeData {
+    FileTreeNodeData {
+        name,
+        ftype: EntryType::Directory,
+        fop,
+        permissions: perm_str_from_u32(0o755),
+        mode: 0o755,
+        uid: 0,
+        gid: 0,
+        mtime: 0,
+        disk_rel_path,
+        vis_rel_path,
+        size: 0,
+        content_hash: None,
+        xattrs: BTreeMap::new(),
+        opaque: false,
+    }
+}
+
 impl FileTreeNode {
-    fn from(relpath: &Path, abspath: &Path) -> Result<FileTreeNode, ImageParcingError> {
-        let metadata = abspath.symlink_metadata()?;
-        let ftype = EntryType::from_ftype(metadata.file_type(), abspath);
-        let permissions = perm_str_from_u32(metadata.permissions().mode());
-        let size = metadata.len();
-
-        let name_to_parse = relpath.file_name();
-        let parent_dir = relpath.parent();
-        let name = match name_to_parse {
-            Some(name) => name.to_string_lossy().to_string(),
-            None => "/".to_string(),
-        };
+    pub fn get_n_children(&self) -> usize {
+        self.children.len()
+    }
+}
 
-        let (name, fop) = parse_name(&name);
-        let vis_rel_path = match parent_dir {
-            Some(parent_dir) => {
-                let mut vis_rel_path = parent_dir.to_path_buf();
-                vis_rel_path.push(&name);
-                vis_rel_path
-            }
-            None => PathBuf::from("/"),
-        };
+/// The data of a node can be read directly from it
+impl Deref for FileTreeNode {
+    type Target = FileTreeNodeData;
 
-        let data = FileTreeNodeData {
-            name,
-            ftype,
-            fop,
-            permissions,
-            disk_rel_path: relpath.to_path_buf(),
-            vis_rel_path,
-            size,
-        };
+    fn deref(&self) -> &FileTreeNodeData {
+        &self.data
+    }
+}
 
-        let node = FileTreeNode {
-            children: RefCell::new(Vec::<Rc<RefCell<FileTreeNode>>>::new()),
-            data,
-        };
+impl FileTreeNodeData {
+    pub fn name(&self) -> &OsStr {
+        &self.name
+    }
 
-        return Ok(node);
+    /// The name in a printable form, see `escape_name`
+    pub fn display_name(&self) -> String {
+        escape_name(&self.name)
     }
 
-    fn from_data(data: &FileTreeNodeData) -> FileTreeNode {
-        let node = FileTreeNode {
-            children: RefCell::new(Vec::<Rc<RefCell<FileTreeNode>>>::new()),
-            data: data.clone(),
-        };
-        return node;
+    pub fn fop(&self) -> EntryOp {
+        self.fop.clone()
     }
 
-    fn get_child(self: &Self, i: usize) -> Option<Rc<RefCell<FileTreeNode>>> {
-        match self.children.borrow().get(i) {
-            Some(child) => Some(child.clone()),
-            None => None,
-        }
+    pub fn path(&self) -> PathBuf {
+        self.vis_rel_path.clone()
     }
 
-    fn add_child(self: &Self, child: Rc<RefCell<FileTreeNode>>) {
-        self.children.borrow_mut().push(child);
+    pub fn ftype(&self) -> EntryType {
+        self.ftype.clone()
     }
 
-    pub fn get_children_names(self: &Self) -> Vec<String> {
-        let mut names: Vec<String> = Vec::new();
-        for child in self.children.borrow().iter() {
-            names.push(child.borrow().data.name.clone());
-        }
-        return names;
+    pub fn is_opaque(&self) -> bool {
+        self.opaque
+    }
+
+    pub fn mode(&self) -> u32 {
+        self.mode
+    }
+
+    pub fn uid(&self) -> u64 {
+        self.uid
+    }
+
+    pub fn gid(&self) -> u64 {
+        self.gid
     }
 
-    pub fn get_children_paths(self: &Self) -> Vec<PathBuf> {
-        let mut paths: Vec<PathBuf> = Vec::new();
-        for child in self.children.borrow().iter() {
-            paths.push(child.borrow().data.vis_rel_path.clone());
+    pub fn mtime(&self) -> u64 {
+        self.mtime
+    }
+
+    /// sha256 of the content, only known for regular files
+    pub fn content_hash(&self) -> Option<&str> {
+        self.content_hash.as_deref()
+    }
+
+    pub fn xattrs(&self) -> &BTreeMap<String, Vec<u8>> {
+        &self.xattrs
+    }
+
+    /// File capabilities in the form `getcap` shows them, e.g. `cap_net_raw=ep`
+    pub fn capabilities(&self) -> Option<String> {
+        self.xattrs
+            .get(CAPABILITY_XATTR)
+            .and_then(|value| describe_capabilities(value))
+    }
+
+    /// Size of the entry; for directories this is the total size of everything below them.
+    /// Hard links take no space of their own, their content is counted with the target.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn permissions(&self) -> &str {
+        &self.permissions
+    }
+
+    /// How this entry differs from `previous`, the entry that was at its path before; None
+    /// when they are the same. Directories are compared without their sizes, which only
+    /// reflect what is below them.
+    pub fn change_from(&self, previous: &FileTreeNodeData) -> Option<PathChange> {
+        let content_changed = self.ftype != previous.ftype
+            || (self.ftype != EntryType::Directory
+                && (self.size != previous.size || self.content_hash != previous.content_hash));
+        if content_changed {
+            Some(PathChange::Modified)
+        } else if self.mode != previous.mode
+            || self.uid != previous.uid
+            || self.gid != previous.gid
+            || self.xattrs != previous.xattrs
+        {
+            Some(PathChange::Metadata)
+        } else {
+            None
         }
-        return paths;
     }
+}
+
+/// A node together with the tree it belongs to, so that its children and parent can be
+/// reached. Cheap to copy; it derefs to the node for its data.
+#[derive(Clone, Copy)]
+pub struct NodeRef<'a> {
+    tree: &'a FileTree,
+    id: NodeId,
+}
+
+impl<'a> Deref for NodeRef<'a> {
+    type Target = FileTreeNode;
 
-    pub fn get_n_children(self: &Self) -> usize {
-        return self.children.borrow().len();
+    fn deref(&self) -> &FileTreeNode {
+        &self.tree.nodes[self.id]
     }
+}
 
-    pub fn name(self: &Self) -> String {
-        return self.data.name.clone();
+impl std::fmt::Debug for NodeRef<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NodeRef")
+            .field("id", &self.id)
+            .field("node", &**self)
+            .finish()
     }
+}
 
-    pub fn fop(self: &Self) -> EntryOp {
-        return self.data.fop.clone();
+impl<'a> NodeRef<'a> {
+    pub fn id(&self) -> NodeId {
+        self.id
     }
 
-    pub fn path(self: &Self) -> PathBuf {
-        return self.data.vis_rel_path.clone();
+    pub fn children(&self) -> impl Iterator<Item = NodeRef<'a>> + use<'a> {
+        let tree = self.tree;
+        self.tree.nodes[self.id]
+            .children
+            .clone()
+            .map(move |id| NodeRef { tree, id })
+    }
+
+    #[allow(dead_code)]
+    pub fn get_child(&self, i: usize) -> Option<NodeRef<'a>> {
+        self.children().nth(i)
     }
 
-    pub fn ftype(self: &Self) -> EntryType {
-        return self.data.ftype.clone();
+    /// Finds a child by name; children are sorted by name, so this is a binary search
+    pub fn child(&self, name: impl AsRef<OsStr>) -> Option<NodeRef<'a>> {
+        let name = name.as_ref();
+        let children = self.tree.nodes[self.id].children.clone();
+        let nodes = &self.tree.nodes[children.clone()];
+        let position = nodes.partition_point(|node| node.data.name.as_os_str() < name);
+        match nodes.get(position) {
+            Some(node) if node.data.name == name => Some(NodeRef {
+                tree: self.tree,
+                id: children.start + position,
+            }),
+            _ => None,
+        }
+    }
+
+    /// The parent, its parent and so on up to the root
+    pub fn ancestors(&self) -> impl Iterator<Item = NodeRef<'a>> + use<'a> {
+        std::iter::successors(self.parent(), |node| node.parent())
+    }
+
+    pub fn parent(&self) -> Option<NodeRef<'a>> {
+        self.tree.nodes[self.id].parent.map(|id| NodeRef {
+            tree: self.tree,
+            id,
+        })
+    }
+
+    #[allow(dead_code)]
+    pub fn get_children_names(&self) -> Vec<OsString> {
+        self.children().map(|child| child.name().to_os_string()).collect()
     }
 }
 
 #[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Debug)]
+#[serde(try_from = "FileTreeArena")]
 pub struct FileTree {
-    parent_node: Rc<RefCell<FileTreeNode>>,
+    // Nodes in breadth-first order, starting with the root. The children of every node are
+    // contiguous and sorted by name.
+    nodes: Vec<FileTreeNode>,
     path_to_parent_node: PathBuf,
 }
 
-#[cfg(unix)]
+/// A FileTree as it is stored, before the indices are known to be valid
+#[derive(Deserialize)]
+struct FileTreeArena {
+    nodes: Vec<FileTreeNode>,
+    path_to_parent_node: PathBuf,
+}
+
+impl TryFrom<FileTreeArena> for FileTree {
+    type Error = String;
+
+    /// Trees are read from the cache, where a truncated or edited entry must not make
+    /// lookups and rendering index out of bounds later
+    fn try_from(arena: FileTreeArena) -> Result<FileTree, String> {
+        let nodes = arena.nodes;
+        match nodes.first() {
+            None => return Err(String::from("tree has no root")),
+            Some(root) if root.parent.is_some() => return Err(String::from("root has a parent")),
+            Some(_) => {}
+        }
+        // The same layout `TreeBuilder::build` produces: breadth-first, every node's
+        // children right after the children of the nodes before it
+        let mut next_free: NodeId = 1;
+        for (id, node) in nodes.iter().enumerate() {
+            // Nodes are placed after their parent, so a node no earlier one claimed is an orphan
+            if id >= next_free {
+                return Err(format!("node {} has no parent", id));
+            }
+            let children = &node.children;
+            if children.start != next_free || children.end < children.start || children.end > nodes.len() {
+                return Err(format!("node {} has children {:?} out of place", id, children));
+            }
+            next_free = children.end;
+            for child in children.clone() {
+                if nodes[child].parent != Some(id) {
+                    return Err(format!("node {} is a child of {} but has parent {:?}", child, id, nodes[child].parent));
+                }
+            }
+            let siblings = &nodes[children.clone()];
+            if siblings.windows(2).any(|pair| pair[0].data.name >= pair[1].data.name) {
+                return Err(format!("children of node {} are not sorted by name", id));
+            }
+        }
+        Ok(FileTree {
+            nodes,
+            path_to_parent_node: arena.path_to_parent_node,
+        })
+    }
+}
+
+/// Permission bits of a mode, including setuid, setgid and sticky
+const MODE_BITS: u32 = 0o7777;
+
 fn perm_str_from_u32(perm: u32) -> String {
     let mut str = String::new();
     if perm & 0o400 != 0 {
@@ -240,253 +578,1012 @@ fn perm_str_from_u32(perm: u32) -> String {
     } else {
         str.push('-');
     }
-    return str;
+    // Special bits take the place of the execute bit they belong to, the way `ls` shows them
+    let mut chars: Vec<char> = str.chars().collect();
+    for (bit, position, set) in [(0o4000, 2, 's'), (0o2000, 5, 's'), (0o1000, 8, 't')] {
+        if perm & bit != 0 {
+            chars[position] = if chars[position] == 'x' {
+                set
+            } else {
+                set.to_ascii_uppercase()
+            };
+        }
+    }
+    chars.into_iter().collect()
+}
+
+/// Node of a TreeBuilder; unlike the nodes of a FileTree its children can be changed
+struct BuilderNode {
+    data: FileTreeNodeData,
+    // Sorted by name
+    children: Vec<NodeId>,
+}
+
+/// Tree that can be changed while a FileTree is assembled. Nodes that are removed stay
+/// in the vector but are not reachable anymore and are dropped by `build`.
+struct TreeBuilder {
+    nodes: Vec<BuilderNode>,
+}
+
+impl TreeBuilder {
+    const ROOT: NodeId = 0;
+
+    fn new(root: FileTreeNodeData) -> TreeBuilder {
+        TreeBuilder {
+            nodes: vec![BuilderNode {
+                data: root,
+                children: Vec::new(),
+            }],
+        }
+    }
+
+    fn from_tree(tree: &FileTree) -> TreeBuilder {
+        let nodes = tree
+            .nodes
+            .iter()
+            .map(|node| BuilderNode {
+                data: node.data.clone(),
+                children: node.children.clone().collect(),
+            })
+            .collect();
+        TreeBuilder { nodes }
+    }
+
+    /// Position of the child among the children of `parent`
+    fn find_child(&self, parent: NodeId, name: &OsStr) -> Option<usize> {
+        let children = &self.nodes[parent].children;
+        let position =
+            children.partition_point(|&child| self.nodes[child].data.name.as_os_str() < name);
+        match children.get(position) {
+            Some(&child) if self.nodes[child].data.name == name => Some(position),
+            _ => None,
+        }
+    }
+
+    /// Adds a child, keeping the children sorted by name
+    fn insert_child(&mut self, parent: NodeId, data: FileTreeNodeData) -> NodeId {
+        let id = self.nodes.len();
+        let position = self.nodes[parent]
+            .children
+            .partition_point(|&child| self.nodes[child].data.name < data.name);
+        self.nodes.push(BuilderNode {
+            data,
+            children: Vec::new(),
+        });
+        self.nodes[parent].children.insert(position, id);
+        id
+    }
+
+    /// Copies `node` of `tree` with everything below it under `parent`
+    fn copy_subtree(&mut self, parent: NodeId, node: NodeRef) {
+        let id = self.insert_child(parent, node.data.clone());
+        for child in node.children() {
+            self.copy_subtree(id, child);
+        }
+    }
+
+    /// Lays the nodes out breadth-first, so that the children of every node are contiguous
+    fn build(self, path_to_parent_node: PathBuf) -> FileTree {
+        let mut nodes: Vec<FileTreeNode> = Vec::with_capacity(self.nodes.len());
+        let mut builder_nodes: Vec<Option<BuilderNode>> = self.nodes.into_iter().map(Some).collect();
+        // Builder ids in the order they are placed, along with their parent in the new tree
+        let mut queue: VecDeque<(NodeId, Option<NodeId>)> = VecDeque::new();
+        queue.push_back((TreeBuilder::ROOT, None));
+        // Children are placed right after everything that is already queued
+        let mut next_free: NodeId = 1;
+
+        while let Some((builder_id, parent)) = queue.pop_front() {
+            let id = nodes.len();
+            let Some(node) = builder_nodes[builder_id].take() else {
+                unreachable!("Every builder node has a single parent");
+            };
+            let children = next_free..next_free + node.children.len();
+            next_free = children.end;
+            for child in node.children {
+                queue.push_back((child, Some(id)));
+            }
+            nodes.push(FileTreeNode {
+                data: node.data,
+                parent,
+                children,
+            });
+        }
+
+        FileTree {
+            nodes,
+            path_to_parent_node,
+        }
+    }
 }
 
 impl FileTree {
+    /// Builds the tree of a layer that was unpacked to `path`. Ownership and device nodes
+    /// are only as good as the unpacking, which usually can't recreate them without root;
+    /// prefer FileTree::from_archive for layer blobs.
+    #[allow(dead_code)]
     pub fn new(path: &Path) -> Result<FileTree, ImageParcingError> {
         let relpath = PathBuf::from("/");
-        let parent_node = Rc::new(RefCell::new(FileTreeNode::from(&relpath, &path)?));
+        let mut builder = TreeBuilder::new(node_data_from_path(&relpath, path)?);
 
-        let mut queue = VecDeque::<Rc<RefCell<FileTreeNode>>>::new();
-        queue.push_front(parent_node.clone());
+        let mut queue = VecDeque::<NodeId>::new();
+        queue.push_front(TreeBuilder::ROOT);
 
-        while !queue.is_empty() {
-            let node = queue
-                .pop_front()
-                .expect("Queue should not be empty at this point, aborting");
-            let node_rel_path = node.borrow().data.disk_rel_path.clone();
+        while let Some(node) = queue.pop_front() {
+            let node_rel_path = builder.nodes[node].data.disk_rel_path.clone();
             // remove the leading slash
             let node_rel_path = node_rel_path
                 .strip_prefix("/")
                 .unwrap_or(&node_rel_path)
                 .to_path_buf();
             let path_to_node = path.join(node_rel_path);
-            let metadata = path_to_node.symlink_metadata()?;
 
-            let ftype = EntryType::from_ftype(metadata.file_type(), &path_to_node);
-            match &ftype {
-                EntryType::Badfile => {
-                    // Do nothing, skip bad files
+            if builder.nodes[node].data.ftype != EntryType::Directory {
+                continue;
+            }
+            let entries = std::fs::read_dir(path_to_node.as_path()).with_path(&path_to_node)?;
+
+            // remove the bad files
+            let mut cleaned_entries: Vec<PathBuf> = Vec::new();
+            for entry in entries {
+                match entry {
+                    Ok(res) => {
+                        cleaned_entries.push(res.path());
+                    }
+                    Err(e) => {
+                        eprintln!("Erroroneous dir entry: {}", e);
+                    }
                 }
-                EntryType::Symlink(_) => {
-                    // Do nothing, skip bad files
+            }
+
+            for entry in cleaned_entries {
+                // The opaque marker is not an entry of its own, it is a property
+                // of the directory it is in
+                if entry.file_name().is_some_and(|name| name == OPAQUE_WHITEOUT) {
+                    builder.nodes[node].data.opaque = true;
+                    continue;
                 }
-                EntryType::File => {
-                    // Do nothing, skip bad files
+                let entry_rel_path =
+                    PathBuf::from("/").join(entry.strip_prefix(path).unwrap_or(&entry));
+                let child = builder.insert_child(node, node_data_from_path(&entry_rel_path, &entry)?);
+                queue.push_back(child);
+            }
+        }
+
+        let mut tree = builder.build(path.to_path_buf());
+        tree.compute_dir_sizes();
+        Ok(tree)
+    }
+
+    /// Builds the tree of a layer from its tar stream without unpacking it. Everything is
+    /// taken from the tar headers, so ownership, special bits and device nodes are kept.
+    /// `archive_path` is only used for error messages.
+    pub fn from_archive<R: Read>(
+        archive: &mut Archive<R>,
+        archive_path: &Path,
+    ) -> Result<FileTree, ImageParcingError> {
+        let mut builder = TreeBuilder::new(FileTree::root_data());
+        // Nodes by their path in the archive; tars do not have to list parent directories
+        // before their contents, or at all
+        let mut nodes: HashMap<PathBuf, NodeId> = HashMap::new();
+        nodes.insert(PathBuf::from("/"), TreeBuilder::ROOT);
+
+        for entry in archive.entries().with_path(archive_path)? {
+            let mut entry = entry.with_path(archive_path)?;
+            let xattrs = read_xattrs(&mut entry).with_path(archive_path)?;
+            let header = entry.header();
+            let mode = header.mode().unwrap_or(0) & MODE_BITS;
+            let (uid, gid) = (header.uid().unwrap_or(0), header.gid().unwrap_or(0));
+            let mtime = header.mtime().unwrap_or(0);
+            let link_name = entry
+                .link_name()
+                .with_path(archive_path)?
+                .map(|link_name| link_name.into_owned());
+            let Some(ftype) = EntryType::from_header(header, link_name) else {
+                continue;
+            };
+            let entry_path = entry.path().with_path(archive_path)?;
+            let Some(disk_rel_path) = normalize_archive_path(&entry_path) else {
+                // The root itself, or a path that escapes it
+                continue;
+            };
+            let parent_path = disk_rel_path.parent().unwrap_or(Path::new("/"));
+            let parent = ensure_dir(&mut builder, &mut nodes, parent_path);
+            let name = disk_rel_path.file_name().unwrap_or_default().to_os_string();
+
+            // The opaque marker is not an entry of its own, it is a property of the directory
+            // it is in
+            if name == OPAQUE_WHITEOUT {
+                builder.nodes[parent].data.opaque = true;
+                continue;
+            }
+
+            let (name, fop) = parse_name(&name);
+            let vis_rel_path = builder.nodes[parent].data.vis_rel_path.join(&name);
+            let (size, content_hash) = match ftype {
+                EntryType::File if fop == EntryOp::Add => {
+                    let hash = HashingReader::new(&mut entry).finalize().with_path(archive_path)?;
+                    (entry.size(), Some(hash))
                 }
-                EntryType::Directory => {
-                    let entries = std::fs::read_dir(path_to_node.as_path())?;
-
-                    // remove the bad files
-                    let mut cleaned_entries: Vec<PathBuf> = Vec::new();
-                    for entry in entries {
-                        match entry {
-                            Ok(res) => {
-                                cleaned_entries.push(res.path());
-                            }
-                            Err(e) => {
-                                println!("Erroroneous dir entry: {}", e);
-                            }
-                        }
-                    }
-                    // sort cleaned entries; no real reason to do that, other than to have a
-                    // consistent order
-                    cleaned_entries.sort();
-
-                    for entry in cleaned_entries {
-                        // println!("Entry: {:?}", entry);
-                        let entry_rel_path = PathBuf::from("/")
-                            .join(entry.strip_prefix(path).unwrap_or(&entry).to_path_buf());
-                        // println!("Entry rel path: {:?}", entry_rel_path);
-                        let child_node =
-                            Rc::new(RefCell::new(FileTreeNode::from(&entry_rel_path, &entry)?));
-                        node.borrow_mut()
-                            .children
-                            .borrow_mut()
-                            .push(child_node.clone());
-                        queue.push_back(child_node.clone());
+                EntryType::File => (entry.size(), None),
+                _ => (0, None),
+            };
+            let data = FileTreeNodeData {
+                name,
+                ftype,
+                fop,
+                permissions: perm_str_from_u32(mode),
+                mode,
+                uid,
+                gid,
+                mtime,
+                disk_rel_path: disk_rel_path.clone(),
+                vis_rel_path,
+                size,
+                content_hash,
+                xattrs,
+                opaque: false,
+            };
+
+            match nodes.get(&disk_rel_path).copied() {
+                // A directory that was implied by its contents, or an entry that is listed
+                // twice; the last header wins
+                Some(existing) => {
+                    if data.ftype != EntryType::Directory {
+                        builder.nodes[existing].children.clear();
+                        nodes.retain(|path, _| {
+                            path == &disk_rel_path || !path.starts_with(&disk_rel_path)
+                        });
                     }
+                    let opaque = builder.nodes[existing].data.opaque;
+                    builder.nodes[existing].data = FileTreeNodeData { opaque, ..data };
+                }
+                None => {
+                    let node = builder.insert_child(parent, data);
+                    nodes.insert(disk_rel_path, node);
                 }
             }
         }
 
-        let tree = FileTree {
-            parent_node,
-            path_to_parent_node: path.to_path_buf(),
-        };
+        let mut tree = builder.build(archive_path.to_path_buf());
+        tree.compute_dir_sizes();
+        Ok(tree)
+    }
 
-        return Ok(tree);
+    fn root_data() -> FileTreeNodeData {
+        implicit_dir_data(
+            OsString::from("/"),
+            EntryOp::Add,
+            PathBuf::from("/"),
+            PathBuf::from("/"),
+        )
     }
 
-    #[allow(dead_code)]
-    pub fn new_from_node(node: Rc<RefCell<FileTreeNode>>) -> FileTree {
-        let tree = FileTree {
-            parent_node: node,
-            path_to_parent_node: PathBuf::from("/"),
-        };
-        return tree;
+    /// Creates a tree that only has the root directory, e.g. as a placeholder for a layer
+    /// that could not be parsed
+    pub fn empty() -> FileTree {
+        TreeBuilder::new(FileTree::root_data()).build(PathBuf::from("/"))
     }
+
     /// This function is used to filter the tree based on the full path
     /// It returns a new tree with the filtered nodes
-    /// If the path is not filtered, it returns a full tree
+    /// If the path is not filtered, it returns the tree itself
     ///
     /// It always returns a tree, even if the path is not found,
     /// But in in this case, it will add GUI error to the return value
-    pub fn filter_tree_full_path(&self, filter: &str) -> (FileTree, Option<GUIError>) {
-        let filter: Vec<&str> = filter.split("/").collect();
-        // clean up empty strings
-        let filter: Vec<&str> = filter.iter().filter(|&x| x != &"").map(|x| *x).collect();
-        if filter.len() == 0 {
-            return (self.clone(), None);
-        }
+    pub fn filter_tree_full_path(&self, filter: &str) -> (Cow<'_, FileTree>, Option<GUIError>) {
+        let filter: Vec<&str> = filter.split("/").filter(|x| !x.is_empty()).collect();
+        let Some((last_filter, parent_filters)) = filter.split_last() else {
+            return (Cow::Borrowed(self), None);
+        };
 
-        let root = self.root();
-        let root_data = &root.borrow().data;
-        let new_root = Rc::new(RefCell::new(FileTreeNode::from_data(root_data)));
-        let mut new_current = new_root.clone();
-        let mut old_current = root.clone();
+        let mut builder = TreeBuilder::new(self.root().data.clone());
+        let mut new_current = TreeBuilder::ROOT;
+        let mut old_current = self.root();
 
         // last search string should be taken care separately as it should not filter when the path is not
-        // yet fully typed
-        for d in 0..filter.len() - 1 {
-            let subfilter: &str = filter[d];
-            let mut next_ind: Option<usize> = None;
-            let children_names = old_current.borrow().get_children_names();
-            for i in 0..children_names.len() {
-                if children_names[i] == subfilter {
-                    next_ind = Some(i);
-                    break;
+        // yet fully typed. Names are matched in the escaped form they are shown in.
+        for subfilter in parent_filters {
+            let old_child = old_current.child(subfilter).or_else(|| {
+                old_current
+                    .children()
+                    .find(|child| child.display_name() == *subfilter)
+            });
+            match old_child {
+                Some(old_child) => {
+                    new_current = builder.insert_child(new_current, old_child.data.clone());
+                    old_current = old_child;
                 }
+                None => return (Cow::Borrowed(self), None),
             }
+        }
 
-            match next_ind {
-                Some(n) => {
-                    let old_child_opt = old_current.borrow().get_child(n);
-                    match &old_child_opt {
-                        Some(_) => {}
-                        None => return (self.clone(), Some(GUIError::CantFilterTree)),
-                    };
-                    let old_child = old_child_opt.unwrap();
-                    let old_child_data = old_child.borrow().data.clone();
-                    let new_child = Rc::new(RefCell::new(FileTreeNode::from_data(&old_child_data)));
-                    new_current.borrow().add_child(new_child.clone());
-                    new_current = new_child.clone();
-                    old_current = old_child.clone();
-                }
-                None => return (self.clone(), None),
+        // Parse last filter
+        let matches: Vec<NodeRef> = old_current
+            .children()
+            .filter(|child| child.display_name().starts_with(last_filter))
+            .collect();
+        if matches.is_empty() {
+            return (Cow::Borrowed(self), Some(GUIError::CantFilterTree));
+        }
+
+        // Matching children keep everything below them
+        for old_child in matches {
+            builder.copy_subtree(new_current, old_child);
+        }
+
+        (
+            Cow::Owned(builder.build(self.path_to_parent_node.clone())),
+            None,
+        )
+    }
+
+    /// Keeps the entries `matches` accepts together with everything below them and the
+    /// directories above them. Entries below a match are not matched on their own.
+    /// Returns the filtered tree and the paths of the matches, or `None` if nothing matches.
+    pub fn filter_matching(
+        &self,
+        mut matches: impl FnMut(NodeRef) -> bool,
+    ) -> Option<(FileTree, Vec<PathBuf>)> {
+        let mut matched: Vec<NodeId> = Vec::new();
+        self.walk(|node| {
+            if node.id() != 0 && matches(node) {
+                matched.push(node.id());
+                WalkControl::SkipChildren
+            } else {
+                WalkControl::Continue
             }
+        });
+        if matched.is_empty() {
+            return None;
         }
 
-        // Parse last filter
-        let subfilter: &str = filter[filter.len() - 1];
-        let mut inds: Vec<usize> = Vec::new();
+        let mut builder = TreeBuilder::new(self.root().data.clone());
+        // Nodes of the builder by the id of the node they copy
+        let mut copies: HashMap<NodeId, NodeId> = HashMap::from([(0, TreeBuilder::ROOT)]);
+        for &id in &matched {
+            let node = self.node(id);
+            let mut ancestors: Vec<NodeRef> = node.ancestors().collect();
+            ancestors.reverse();
+            let mut parent = TreeBuilder::ROOT;
+            for ancestor in ancestors.iter().skip(1) {
+                parent = *copies
+                    .entry(ancestor.id())
+                    .or_insert_with(|| builder.insert_child(parent, ancestor.data.clone()));
+            }
+            builder.copy_subtree(parent, node);
+        }
+
+        let paths = matched.iter().map(|&id| self.node(id).path()).collect();
+        Some((builder.build(self.path_to_parent_node.clone()), paths))
+    }
 
-        let children_names = old_current.borrow().get_children_names();
-        for i in 0..children_names.len() {
-            if children_names[i].starts_with(subfilter) {
-                inds.push(i);
+    /// Applies a layer on top of this tree, the way layers are stacked in a container:
+    /// entries of the layer are added or replace existing ones, whiteouts remove entries
+    /// of the lower layers, and directories present in both are merged.
+    pub fn apply_layer(&mut self, layer: &FileTree) {
+        let mut builder = TreeBuilder::from_tree(self);
+        apply_node(&mut builder, TreeBuilder::ROOT, layer.root());
+        *self = builder.build(self.path_to_parent_node.clone());
+        // Directories took the sizes of the layer, which only count what the layer added
+        self.compute_dir_sizes();
+    }
+
+    /// The node of `other` at the same path as each node of this tree, by node id
+    pub fn match_nodes(&self, other: &FileTree) -> Vec<Option<NodeId>> {
+        let mut matched: Vec<Option<NodeId>> = vec![None; self.nodes.len()];
+        matched[0] = Some(0);
+        // Parents come before their children, so theirs is always known first
+        for node in self.iter().skip(1) {
+            matched[node.id()] = node
+                .parent()
+                .and_then(|parent| matched[parent.id()])
+                .and_then(|other_parent| other.node(other_parent).child(node.name()))
+                .map(|other_node| other_node.id());
+        }
+        matched
+    }
+
+    /// Returns a copy of this layer in which added entries that overwrite an existing path of
+    /// `lower` are marked as modified. `lower` is the merged filesystem of the layers below.
+    pub fn classify_against(&self, lower: &FileTree) -> FileTree {
+        let mut classified = self.clone();
+        let lower_nodes = self.match_nodes(lower);
+        for node in self.iter().skip(1) {
+            let lower_node = lower_nodes[node.id()].map(|id| lower.node(id));
+
+            // Directories are in every layer that writes below them, they only count as
+            // modified if their own metadata changed
+            let modified = lower_node.is_some_and(|lower_node| {
+                node.data.ftype != EntryType::Directory || node.data.change_from(&lower_node.data).is_some()
+            });
+            let data = &mut classified.nodes[node.id()].data;
+            if data.fop == EntryOp::Add && modified {
+                data.fop = EntryOp::Modify;
             }
         }
+        classified
+    }
 
-        if inds.len() == 0 {
-            return (self.clone(), Some(GUIError::CantFilterTree));
+    /// Counts the entries of the tree by operation, the root is not counted
+    pub fn op_counts(&self) -> OpCounts {
+        let mut counts = OpCounts::default();
+        for node in self.iter().skip(1) {
+            match node.data.fop {
+                EntryOp::Add => counts.added += 1,
+                EntryOp::Modify => counts.modified += 1,
+                EntryOp::Remove => counts.removed += 1,
+            }
         }
+        counts
+    }
 
-        let mut new_children = Vec::<Rc<RefCell<FileTreeNode>>>::new();
+    /// Merges the layers in order, yielding the filesystem as it is seen after the last one
+    pub fn merge(layers: &[&FileTree]) -> FileTree {
+        let mut builder = TreeBuilder::new(FileTree::root_data());
+        for layer in layers {
+            apply_node(&mut builder, TreeBuilder::ROOT, layer.root());
+        }
+        let mut merged = builder.build(PathBuf::from("/"));
+        merged.compute_dir_sizes();
+        merged
+    }
 
-        for i in inds {
-            // Get the orig child node
-            let old_child_opt = old_current.borrow().get_child(i);
-            match &old_child_opt {
-                Some(_) => {}
-                None => return (self.clone(), Some(GUIError::CantFilterTree)),
+    /// Compares this filesystem with a later one, e.g. two merged filesystems of an image
+    pub fn diff(&self, new: &FileTree) -> TreeDiff {
+        let mut builder = TreeBuilder::new(new.root().data.clone());
+        let mut entries: HashMap<PathBuf, DiffEntry> = HashMap::new();
+        let old_nodes = new.match_nodes(self);
+        // The node of the builder that copies each node of `new`
+        let mut copies: Vec<Option<NodeId>> = vec![None; new.len()];
+        copies[0] = Some(TreeBuilder::ROOT);
+
+        for node in new.iter().skip(1) {
+            let old = old_nodes[node.id()].map(|id| self.node(id));
+            let change = match old {
+                None => Some(PathChange::Added),
+                Some(old) => node.data.change_from(&old.data),
+            };
+            let Some(change) = change else {
+                continue;
             };
-            let old_child = old_child_opt.unwrap();
 
-            let old_child_data = old_child.borrow().data.clone();
-            let new_child = Rc::new(RefCell::new(FileTreeNode::from_data(&old_child_data)));
-            // Last child should also have original node chidren added to keeep the tree
-            new_child.borrow_mut().children = old_child.borrow_mut().children.clone();
-            new_children.push(new_child.clone());
+            // The directories above may not have changed themselves
+            let parent = node.parent().map_or(TreeBuilder::ROOT, |parent| {
+                copy_with_ancestors(&mut builder, &mut copies, &mut entries, self, parent)
+            });
+            let fop = match change {
+                PathChange::Added => EntryOp::Add,
+                _ => EntryOp::Modify,
+            };
+            copies[node.id()] = Some(builder.insert_child(parent, FileTreeNodeData { fop, ..node.data.clone() }));
+            entries.insert(
+                node.path(),
+                DiffEntry {
+                    change: Some(change),
+                    size_delta: node.size as i64 - old.map_or(0, |old| old.size) as i64,
+                },
+            );
         }
 
-        // sort children by name
-        new_children.sort_by(|a, b| {
-            let a_name = a.borrow().data.name.clone();
-            let b_name = b.borrow().data.name.clone();
-            a_name.cmp(&b_name)
+        // Entries of `self` that are gone, under the directories they were in
+        self.walk(|old| {
+            if old.id() == 0 {
+                return WalkControl::Continue;
+            }
+            let new_parent = old.parent().and_then(|parent| new.get(parent.path()));
+            match new_parent.and_then(|parent| parent.child(old.name())) {
+                Some(_) => WalkControl::Continue,
+                None => {
+                    if let Some(new_parent) = new_parent.filter(|parent| parent.ftype == EntryType::Directory) {
+                        let parent = copy_with_ancestors(&mut builder, &mut copies, &mut entries, self, new_parent);
+                        copy_removed(&mut builder, &mut entries, parent, old);
+                    }
+                    WalkControl::SkipChildren
+                }
+            }
         });
-        new_current
-            .borrow()
-            .children
-            .borrow_mut()
-            .extend(new_children);
 
-        let new_tree = FileTree {
-            parent_node: new_root,
-            path_to_parent_node: self.path_to_parent_node.clone(),
-        };
-        return (new_tree, None);
+        entries.insert(
+            PathBuf::from("/"),
+            DiffEntry {
+                change: None,
+                size_delta: new.root().size as i64 - self.root().size as i64,
+            },
+        );
+        TreeDiff {
+            tree: builder.build(new.path_to_parent_node.clone()),
+            entries,
+        }
     }
 
-    pub fn root(&self) -> Rc<RefCell<FileTreeNode>> {
-        self.parent_node.clone()
+    /// Finds the node at a path as it is shown to the user, e.g. `/usr/bin/ls`.
+    /// Takes one binary search per path component.
+    pub fn get(&self, path: impl AsRef<Path>) -> Option<NodeRef<'_>> {
+        let mut node = self.root();
+        let path = path.as_ref();
+        for component in path.components() {
+            let Component::Normal(name) = component else {
+                continue;
+            };
+            node = node.child(name)?;
+        }
+        Some(node)
     }
 
-    pub fn iter(&self) -> BreadthFirstIterator {
-        let root = self.root();
-        BreadthFirstIterator::new(root)
+    /// Resolves a path the way the kernel would in this filesystem: symlinks are followed
+    /// in every component including the last one, relative targets are taken from the
+    /// directory of the link and `..` never leaves the root
+    pub fn resolve(&self, path: impl AsRef<Path>) -> LinkTarget {
+        // Directories from the root to where the resolution currently is
+        let mut current: Vec<NodeId> = vec![0];
+        let mut remaining: VecDeque<OsString> = VecDeque::new();
+        push_components(&mut remaining, &mut current, path.as_ref());
+        let mut hops = 0;
+
+        while let Some(name) = remaining.pop_front() {
+            if name == ".." {
+                if current.len() > 1 {
+                    current.pop();
+                }
+                continue;
+            }
+            let parent = self.node(*current.last().unwrap_or(&0));
+            // Whiteouts only hide things, they are not entries that can be resolved to
+            let Some(node) = parent.child(&name).filter(|node| node.fop() != EntryOp::Remove)
+            else {
+                return LinkTarget::Dangling;
+            };
+            match &node.data.ftype {
+                EntryType::Symlink(target) => {
+                    hops += 1;
+                    if hops > MAX_SYMLINK_HOPS {
+                        return LinkTarget::Loop;
+                    }
+                    push_components(&mut remaining, &mut current, target);
+                }
+                EntryType::Directory => current.push(node.id()),
+                _ if remaining.is_empty() => return LinkTarget::Resolved(node.id()),
+                // Only directories can have something below them
+                _ => return LinkTarget::Dangling,
+            }
+        }
+        LinkTarget::Resolved(*current.last().unwrap_or(&0))
     }
 
-    #[allow(dead_code)]
-    fn get_node_by_name(&self, name: &str) -> Option<Rc<RefCell<FileTreeNode>>> {
+    /// Paths of the regular files of the tree grouped by their content hash
+    pub fn files_by_content(&self) -> HashMap<String, Vec<PathBuf>> {
+        let mut files: HashMap<String, Vec<PathBuf>> = HashMap::new();
         for node in self.iter() {
-            if node.borrow().data.name == name {
-                return Some(node.clone());
+            if let Some(hash) = node.content_hash() {
+                files.entry(hash.to_string()).or_default().push(node.path());
             }
         }
-        None
+        files
+    }
+
+    pub fn root(&self) -> NodeRef<'_> {
+        self.node(0)
+    }
+
+    pub fn node(&self, id: NodeId) -> NodeRef<'_> {
+        NodeRef { tree: self, id }
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Iterates over the nodes breadth-first, which is the order they are stored in
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = NodeRef<'_>> + ExactSizeIterator {
+        (0..self.nodes.len()).map(move |id| self.node(id))
+    }
+
+    /// Iterates over the nodes depth-first, each directory before its contents
+    #[allow(dead_code)]
+    pub fn iter_depth_first(&self) -> DepthFirstIterator<'_> {
+        DepthFirstIterator {
+            tree: self,
+            stack: vec![0],
+        }
+    }
+
+    /// Iterates over the nodes depth-first, each directory after its contents
+    #[allow(dead_code)]
+    pub fn iter_post_order(&self) -> PostOrderIterator<'_> {
+        PostOrderIterator {
+            tree: self,
+            stack: vec![(0, false)],
+        }
+    }
+
+    /// Visits the nodes depth-first, each directory before its contents. The visitor decides
+    /// whether to descend into the node it was given, skip its contents or stop the walk.
+    pub fn walk<F: FnMut(NodeRef<'_>) -> WalkControl>(&self, mut visit: F) {
+        let mut stack: Vec<NodeId> = vec![0];
+        while let Some(id) = stack.pop() {
+            match visit(self.node(id)) {
+                WalkControl::Continue => stack.extend(self.nodes[id].children.clone().rev()),
+                WalkControl::SkipChildren => {}
+                WalkControl::Stop => return,
+            }
+        }
+    }
+
+    /// Sets the size of every directory to the total size of its contents.
+    /// Whiteouts take no space and are not counted.
+    fn compute_dir_sizes(&mut self) {
+        // Children come after their parents, so going backwards sees them first
+        for id in (0..self.nodes.len()).rev() {
+            if self.nodes[id].data.ftype != EntryType::Directory {
+                continue;
+            }
+            let size = self.nodes[id]
+                .children
+                .clone()
+                .filter(|&child| self.nodes[child].data.fop != EntryOp::Remove)
+                .map(|child| self.nodes[child].data.size)
+                .sum();
+            self.nodes[id].data.size = size;
+        }
+    }
+}
+
+/// Symlinks that are followed at most while resolving a path, the same limit Linux has
+const MAX_SYMLINK_HOPS: usize = 40;
+
+/// Differences between two filesystems, see FileTree::diff
+pub struct TreeDiff {
+    /// The changed entries and the directories above them. Removed entries are kept with
+    /// their old data and marked as removed.
+    pub tree: FileTree,
+    // Every entry of the tree by path
+    entries: HashMap<PathBuf, DiffEntry>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DiffEntry {
+    /// None for directories that are only there because of the changes below them
+    pub change: Option<PathChange>,
+    /// Growth of the entry in bytes; directories count everything below them
+    pub size_delta: i64,
+}
+
+impl TreeDiff {
+    pub fn entry(&self, path: &Path) -> Option<DiffEntry> {
+        self.entries.get(path).copied()
+    }
+
+    /// Growth of the whole filesystem in bytes
+    pub fn size_delta(&self) -> i64 {
+        self.entry(Path::new("/")).map_or(0, |entry| entry.size_delta)
+    }
+
+    /// Number of entries per kind of change
+    pub fn counts(&self) -> HashMap<PathChange, usize> {
+        let mut counts = HashMap::new();
+        for change in self.entries.values().filter_map(|entry| entry.change) {
+            *counts.entry(change).or_default() += 1;
+        }
+        counts
     }
 }
 
-// Define the iterator struct for breadth-first traversal
-pub struct BreadthFirstIterator {
-    queue: VecDeque<Rc<RefCell<FileTreeNode>>>,
+/// Where a path leads once all symlinks on the way are followed
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum LinkTarget {
+    /// The entry the path ends at
+    Resolved(NodeId),
+    /// Something on the way does not exist
+    Dangling,
+    /// The links lead to each other without end
+    Loop,
 }
 
-impl BreadthFirstIterator {
-    fn new(root_node: Rc<RefCell<FileTreeNode>>) -> Self {
-        let mut queue = VecDeque::new();
-        queue.push_back(root_node);
-        BreadthFirstIterator { queue }
+/// Queues the components of `path` in front of the ones still to be resolved; absolute paths
+/// start over at the root
+fn push_components(remaining: &mut VecDeque<OsString>, current: &mut Vec<NodeId>, path: &Path) {
+    if path.has_root() {
+        current.truncate(1);
     }
+    for component in path.components().rev() {
+        match component {
+            Component::Normal(name) => remaining.push_front(name.to_os_string()),
+            Component::ParentDir => remaining.push_front(OsString::from("..")),
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => {}
+        }
+    }
+}
+
+/// What `FileTree::walk` does after visiting a node
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum WalkControl {
+    Continue,
+    SkipChildren,
+    #[allow(dead_code)]
+    Stop,
+}
+
+#[allow(dead_code)]
+pub struct DepthFirstIterator<'a> {
+    tree: &'a FileTree,
+    stack: Vec<NodeId>,
 }
 
-impl Iterator for BreadthFirstIterator {
-    type Item = Rc<RefCell<FileTreeNode>>;
+impl<'a> Iterator for DepthFirstIterator<'a> {
+    type Item = NodeRef<'a>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some(node) = self.queue.pop_front() {
-            let node_ref = node.borrow();
-            let children_vec = node_ref.children.borrow();
-            for child in children_vec.iter() {
-                self.queue.push_back(child.clone());
+        let id = self.stack.pop()?;
+        // Reversed, so that the first child is the next to be popped
+        self.stack.extend(self.tree.nodes[id].children.clone().rev());
+        Some(self.tree.node(id))
+    }
+}
+
+#[allow(dead_code)]
+pub struct PostOrderIterator<'a> {
+    tree: &'a FileTree,
+    // Nodes along with whether their children have been pushed already
+    stack: Vec<(NodeId, bool)>,
+}
+
+impl<'a> Iterator for PostOrderIterator<'a> {
+    type Item = NodeRef<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (id, expanded) = self.stack.pop()?;
+            if expanded {
+                return Some(self.tree.node(id));
             }
-            Some(node.clone())
-        } else {
-            None
+            self.stack.push((id, true));
+            let children = self.tree.nodes[id].children.clone().rev();
+            self.stack.extend(children.map(|child| (child, false)));
+        }
+    }
+}
+
+/// Prefix of the PAX records that carry extended attributes
+const PAX_XATTR_PREFIX: &str = "SCHILY.xattr.";
+
+/// Extended attributes of a tar entry, taken from its PAX records
+fn read_xattrs<R: Read>(entry: &mut Entry<R>) -> std::io::Result<BTreeMap<String, Vec<u8>>> {
+    let mut xattrs: BTreeMap<String, Vec<u8>> = BTreeMap::new();
+    if let Some(extensions) = entry.pax_extensions()? {
+        for extension in extensions {
+            let extension = extension?;
+            if let Some(name) = extension.key().ok().and_then(|key| key.strip_prefix(PAX_XATTR_PREFIX)) {
+                xattrs.insert(name.to_string(), extension.value_bytes().to_vec());
+            }
+        }
+    }
+    Ok(xattrs)
+}
+
+/// Turns a path of a tar entry into an absolute path in the layer, e.g. `./usr/bin/` into
+/// `/usr/bin`. Returns `None` for the root and for paths that point outside of the layer.
+fn normalize_archive_path(path: &Path) -> Option<PathBuf> {
+    let mut normalized = PathBuf::from("/");
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => {}
+            Component::ParentDir => return None,
+        }
+    }
+    if normalized == Path::new("/") {
+        None
+    } else {
+        Some(normalized)
+    }
+}
+
+/// Returns the directory node at `path`, creating it and its parents if the archive
+/// has not listed them
+fn ensure_dir(
+    builder: &mut TreeBuilder,
+    nodes: &mut HashMap<PathBuf, NodeId>,
+    path: &Path,
+) -> NodeId {
+    if let Some(&node) = nodes.get(path) {
+        return node;
+    }
+    let parent = ensure_dir(builder, nodes, path.parent().unwrap_or(Path::new("/")));
+    let name = path.file_name().unwrap_or_default();
+    let (name, fop) = parse_name(name);
+    let vis_rel_path = builder.nodes[parent].data.vis_rel_path.join(&name);
+    let data = implicit_dir_data(name, fop, path.to_path_buf(), vis_rel_path);
+    let node = builder.insert_child(parent, data);
+    nodes.insert(path.to_path_buf(), node);
+    node
+}
+
+/// Formats a size in bytes with binary units, e.g. `1.5 MB`
+pub fn format_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["KB", "MB", "GB", "TB", "PB"];
+    if size < 1024 {
+        return format!("{} B", size);
+    }
+    let mut value = size as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// Formats a change of size with its sign, e.g. `+1.5 MB` or `-12 B`
+pub fn format_size_delta(delta: i64) -> String {
+    let sign = if delta < 0 { '-' } else { '+' };
+    format!("{}{}", sign, format_size(delta.unsigned_abs()))
+}
+
+/// Copies `node` of the new tree of a diff and the directories above it into the builder,
+/// unless they are there already; returns the builder node of `node`
+fn copy_with_ancestors(
+    builder: &mut TreeBuilder,
+    copies: &mut [Option<NodeId>],
+    entries: &mut HashMap<PathBuf, DiffEntry>,
+    old: &FileTree,
+    node: NodeRef,
+) -> NodeId {
+    if let Some(copy) = copies[node.id()] {
+        return copy;
+    }
+    let parent = match node.parent() {
+        Some(parent) => copy_with_ancestors(builder, copies, entries, old, parent),
+        None => TreeBuilder::ROOT,
+    };
+    let old_size = old.get(node.path()).map_or(0, |old| old.size);
+    entries.insert(
+        node.path(),
+        DiffEntry {
+            change: None,
+            size_delta: node.size as i64 - old_size as i64,
+        },
+    );
+    let copy = builder.insert_child(parent, node.data.clone());
+    copies[node.id()] = Some(copy);
+    copy
+}
+
+/// Copies `node` of the old tree of a diff and everything below it, marked as removed
+fn copy_removed(builder: &mut TreeBuilder, entries: &mut HashMap<PathBuf, DiffEntry>, parent: NodeId, node: NodeRef) {
+    entries.insert(
+        node.path(),
+        DiffEntry {
+            change: Some(PathChange::Removed),
+            size_delta: -(node.size as i64),
+        },
+    );
+    let copy = builder.insert_child(
+        parent,
+        FileTreeNodeData {
+            fop: EntryOp::Remove,
+            ..node.data.clone()
+        },
+    );
+    for child in node.children() {
+        copy_removed(builder, entries, copy, child);
+    }
+}
+
+/// Applies the children of `upper` on top of the children of `lower`.
+/// The layer is only read, the merged tree gets its own copies of the nodes.
+fn apply_node(builder: &mut TreeBuilder, lower: NodeId, upper: NodeRef) {
+    for upper_child in upper.children() {
+        let upper_data = upper_child.data.clone();
+        let position = builder.find_child(lower, &upper_data.name);
+
+        if upper_data.fop == EntryOp::Remove {
+            if let Some(i) = position {
+                builder.nodes[lower].children.remove(i);
+            }
+            continue;
+        }
+
+        let existing = position.map(|i| builder.nodes[lower].children[i]);
+        match existing {
+            Some(lower_child)
+                if builder.nodes[lower_child].data.ftype == EntryType::Directory
+                    && upper_data.ftype == EntryType::Directory =>
+            {
+                let opaque = upper_data.opaque;
+                // The merged tree already reflects the opaque directory, it hides nothing
+                // in it anymore
+                builder.nodes[lower_child].data = FileTreeNodeData {
+                    opaque: false,
+                    ..upper_data
+                };
+                if opaque {
+                    builder.nodes[lower_child].children.clear();
+                }
+                apply_node(builder, lower_child, upper_child);
+            }
+            _ => {
+                if let Some(i) = position {
+                    builder.nodes[lower].children.remove(i);
+                }
+                let new_child = builder.insert_child(
+                    lower,
+                    FileTreeNodeData {
+                        opaque: false,
+                        ..upper_data
+                    },
+                );
+                // Whiteouts inside a freshly added directory have nothing to remove,
+                // applying the directory to the empty node takes care of skipping them
+                apply_node(builder, new_child, upper_child);
+            }
+        }
+    }
+}
+
+/// An entry of a layer made by `test_layer`: a path, or a path with its content and
+/// optionally its mode. Paths ending with '/' are directories; files without a given
+/// content contain their own path.
+#[cfg(test)]
+#[derive(Clone, Copy)]
+pub(crate) struct TestFile<'a> {
+    path: &'a str,
+    content: Option<&'a str>,
+    mode: Option<u32>,
+}
+
+#[cfg(test)]
+impl<'a> From<&'a str> for TestFile<'a> {
+    fn from(path: &'a str) -> Self {
+        TestFile { path, content: None, mode: None }
+    }
+}
+
+#[cfg(test)]
+impl<'a> From<(&'a str, &'a str)> for TestFile<'a> {
+    fn from((path, content): (&'a str, &'a str)) -> Self {
+        TestFile { path, content: Some(content), mode: None }
+    }
+}
+
+#[cfg(test)]
+impl<'a> From<(&'a str, &'a str, u32)> for TestFile<'a> {
+    fn from((path, content, mode): (&'a str, &'a str, u32)) -> Self {
+        TestFile { path, content: Some(content), mode: Some(mode) }
+    }
+}
+
+/// Builds the tree of a layer with the given entries, for tests
+#[cfg(all(test, unix))]
+pub(crate) fn test_layer<'a, F: Into<TestFile<'a>> + Copy>(files: &[F]) -> FileTree {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::TempDir::new().unwrap();
+    for &file in files {
+        let file: TestFile = file.into();
+        let full_path = dir.path().join(file.path);
+        if file.path.ends_with('/') {
+            std::fs::create_dir_all(&full_path).unwrap();
+            continue;
+        }
+        std::fs::create_dir_all(full_path.parent().unwrap()).unwrap();
+        std::fs::write(&full_path, file.content.unwrap_or(file.path)).unwrap();
+        if let Some(mode) = file.mode {
+            std::fs::set_permissions(&full_path, std::fs::Permissions::from_mode(mode)).unwrap();
         }
     }
+    FileTree::new(dir.path()).unwrap()
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{EntryOp, EntryType, FileTree};
+    use super::{
+        EntryOp, EntryType, FileTree, LinkTarget, NodeRef, OpCounts, PathChange, WalkControl,
+        format_size, format_size_delta, perm_str_from_u32, test_layer,
+    };
     use crate::exceptions::GUIError;
     use assert_matches::assert_matches;
     use std::fs::File;
-    use std::path::PathBuf;
+    use std::path::{Path, PathBuf};
 
     fn construct_tree() -> FileTree {
         let project_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
@@ -498,7 +1595,7 @@ mod tests {
     fn tree() {
         let tree = construct_tree();
         for nodes in tree.iter() {
-            let node = nodes.borrow();
+            let node = nodes;
             if node.data.name == "subtest3" {
                 assert_eq!(node.data.ftype, EntryType::Directory);
                 assert_eq!(node.data.fop, EntryOp::Remove);
@@ -549,7 +1646,25 @@ mod tests {
         let tree = construct_tree();
         let serialised = serde_json::to_string(&tree).unwrap();
         let deserialised: FileTree = serde_json::from_str(&serialised).unwrap();
-        assert_eq!(tree.parent_node, deserialised.parent_node);
+        assert_eq!(tree, deserialised);
+    }
+
+    #[test]
+    fn corrupt_tree_is_rejected() {
+        let tree = construct_tree();
+        let valid = serde_json::to_value(&tree).unwrap();
+        let corrupt = |edit: &dyn Fn(&mut serde_json::Value)| {
+            let mut value = valid.clone();
+            edit(&mut value);
+            serde_json::from_value::<FileTree>(value)
+        };
+        assert!(corrupt(&|_| {}).is_ok());
+        assert!(corrupt(&|tree| tree["nodes"] = serde_json::json!([])).is_err());
+        // Truncated, so the children of the root point past the end
+        assert!(corrupt(&|tree| tree["nodes"].as_array_mut().unwrap().truncate(2)).is_err());
+        assert!(corrupt(&|tree| tree["nodes"][0]["children"]["end"] = serde_json::json!(1000)).is_err());
+        assert!(corrupt(&|tree| tree["nodes"][1]["parent"] = serde_json::json!(1)).is_err());
+        assert!(corrupt(&|tree| tree["nodes"][0]["parent"] = serde_json::json!(0)).is_err());
     }
 
     #[test]
@@ -559,10 +1674,10 @@ mod tests {
         assert_matches!(err, None);
         // First three nodes should be subtest, subtest2 and subtest3
         let root = filtered_tree.root();
-        let names = root.borrow().get_children_names();
+        let names = root.get_children_names();
 
         for name in names.iter() {
-            println!("Name: {}", name);
+            println!("Name: {:?}", name);
         }
 
         assert_eq!(names.len(), 3);
@@ -571,16 +1686,16 @@ mod tests {
         assert_eq!(names[2], "subtest3");
 
         // Check that after filter children are present
-        let subtest_node = tree.get_node_by_name("subtest");
+        let subtest_node = tree.get("/subtest");
         assert_matches!(subtest_node, Some(_));
         let subtest_node = subtest_node.unwrap();
-        let subtest_children = subtest_node.borrow().get_children_names();
+        let subtest_children = subtest_node.get_children_names();
         assert_eq!(subtest_children.len(), 2);
         assert_eq!(subtest_children[0], "subsubtest");
         assert_eq!(subtest_children[1], "subtestfile");
 
         for name in subtest_children.iter() {
-            println!("Name: {}", name);
+            println!("Name: {:?}", name);
         }
     }
 
@@ -591,26 +1706,26 @@ mod tests {
         assert_matches!(err, None);
         // First three nodes should be subtest, subtest2 and subtest3
         let root = filtered_tree.root();
-        let names = root.borrow().get_children_names();
+        let names = root.get_children_names();
 
         for name in names.iter() {
-            println!("Name: {}", name);
+            println!("Name: {:?}", name);
         }
 
         assert_eq!(names.len(), 1);
         assert_eq!(names[0], "subtest");
 
         // Check that after filter children are present
-        let subtest_node = tree.get_node_by_name("subtest");
+        let subtest_node = tree.get("/subtest");
         assert_matches!(subtest_node, Some(_));
         let subtest_node = subtest_node.unwrap();
-        let subtest_children = subtest_node.borrow().get_children_names();
+        let subtest_children = subtest_node.get_children_names();
         assert_eq!(subtest_children.len(), 2);
         assert_eq!(subtest_children[0], "subsubtest");
         assert_eq!(subtest_children[1], "subtestfile");
 
         for name in subtest_children.iter() {
-            println!("Name: {}", name);
+            println!("Name: {:?}", name);
         }
     }
 
@@ -623,22 +1738,581 @@ mod tests {
         let (filtered_tree, error) = layer_tree.filter_tree_full_path("/etc/");
         assert_matches!(error, None);
         let root = filtered_tree.root();
-        let children_names = root.borrow().get_children_names();
+        let children_names = root.get_children_names();
         assert_eq!(children_names.len(), 1);
         assert_eq!(children_names[0], "etc");
-        let etc_node = root.borrow().get_child(0).unwrap();
-        assert!(etc_node.borrow().get_n_children() > 0);
-        let etc_children = etc_node.borrow().get_children_names();
+        let etc_node = root.get_child(0).unwrap();
+        assert!(etc_node.get_n_children() > 0);
+        let etc_children = etc_node.get_children_names();
         let apt_cache_name = etc_children.iter().position(|name| name == "apt").unwrap();
-        let apt_node = etc_node.borrow().get_child(apt_cache_name).unwrap();
+        let apt_node = etc_node.get_child(apt_cache_name).unwrap();
         let trusted = apt_node
-            .borrow()
             .get_children_names()
             .iter()
             .position(|name| name == "trusted.gpg.d")
             .unwrap();
-        let trusted_node = apt_node.borrow().get_child(trusted).unwrap();
-        assert_eq!(trusted_node.borrow().get_n_children(), 2);
+        let trusted_node = apt_node.get_child(trusted).unwrap();
+        assert_eq!(trusted_node.get_n_children(), 2);
+    }
+
+    fn tar_entry(
+        builder: &mut tar::Builder<Vec<u8>>,
+        path: impl AsRef<std::path::Path>,
+        entry_type: tar::EntryType,
+        mode: u32,
+        content: &[u8],
+    ) {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_mode(mode);
+        header.set_uid(1000);
+        header.set_gid(100);
+        header.set_mtime(1743002389);
+        header.set_size(content.len() as u64);
+        header.set_path(path).unwrap();
+        header.set_cksum();
+        builder.append(&header, content).unwrap();
+    }
+
+    fn archive_tree(build: impl FnOnce(&mut tar::Builder<Vec<u8>>)) -> FileTree {
+        let mut builder = tar::Builder::new(Vec::new());
+        build(&mut builder);
+        let bytes = builder.into_inner().unwrap();
+        let mut archive = tar::Archive::new(&bytes[..]);
+        FileTree::from_archive(&mut archive, std::path::Path::new("layer.tar")).unwrap()
+    }
+
+    #[test]
+    fn tree_from_archive() {
+        let tree = archive_tree(|builder| {
+            // Contents before their directory, and a directory that is never listed
+            tar_entry(builder, "./usr/bin/passwd", tar::EntryType::Regular, 0o4755, b"binary");
+            tar_entry(builder, "./usr/", tar::EntryType::Directory, 0o755, b"");
+            tar_entry(builder, "./tmp/", tar::EntryType::Directory, 0o1777, b"");
+            tar_entry(builder, "./etc/.wh.hosts", tar::EntryType::Regular, 0o644, b"");
+            tar_entry(builder, "./var/cache/.wh..wh..opq", tar::EntryType::Regular, 0o644, b"");
+
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Char);
+            header.set_mode(0o666);
+            header.set_device_major(1).unwrap();
+            header.set_device_minor(3).unwrap();
+            header.set_size(0);
+            header.set_path("dev/null").unwrap();
+            header.set_cksum();
+            builder.append(&header, &[][..]).unwrap();
+
+            tar_entry(builder, "./run/initctl", tar::EntryType::Fifo, 0o600, b"");
+
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Link);
+            header.set_mode(0o4755);
+            header.set_size(0);
+            header.set_path("usr/bin/chfn").unwrap();
+            header.set_link_name("usr/bin/passwd").unwrap();
+            header.set_cksum();
+            builder.append(&header, &[][..]).unwrap();
+
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Symlink);
+            header.set_mode(0o777);
+            header.set_size(0);
+            header.set_path("bin").unwrap();
+            header.set_link_name("usr/bin").unwrap();
+            header.set_cksum();
+            builder.append(&header, &[][..]).unwrap();
+        });
+
+        assert_eq!(
+            tree.root().get_children_names(),
+            vec!["bin", "dev", "etc", "run", "tmp", "usr", "var"]
+        );
+
+        let passwd = tree.get("/usr/bin/passwd").unwrap();
+        assert_eq!(passwd.ftype(), EntryType::File);
+        assert_eq!(passwd.mode(), 0o4755);
+        assert_eq!(passwd.permissions(), "rwsr-xr-x");
+        assert_eq!((passwd.uid(), passwd.gid()), (1000, 100));
+        assert_eq!(passwd.mtime(), 1743002389);
+        assert_eq!(passwd.size(), 6);
+
+        let node = |path: &str| tree.get(path).unwrap();
+        assert_eq!(node("/usr/bin").ftype(), EntryType::Directory);
+        assert_eq!(node("/tmp").permissions(), "rwxrwxrwt");
+        assert_eq!(node("/etc/hosts").fop(), EntryOp::Remove);
+        assert!(node("/var/cache").is_opaque());
+        assert_eq!(node("/var/cache").get_n_children(), 0);
+        assert_eq!(
+            node("/dev/null").ftype(),
+            EntryType::CharDevice { major: 1, minor: 3 }
+        );
+        assert_eq!(node("/run/initctl").ftype(), EntryType::Fifo);
+        assert_eq!(
+            node("/usr/bin/chfn").ftype(),
+            EntryType::Hardlink(PathBuf::from("/usr/bin/passwd"))
+        );
+        assert_eq!(
+            node("/bin").ftype(),
+            EntryType::Symlink(PathBuf::from("usr/bin"))
+        );
+    }
+
+    #[test]
+    fn content_hashes() {
+        let tree = archive_tree(|builder| {
+            tar_entry(builder, "etc/a.conf", tar::EntryType::Regular, 0o644, b"same");
+            tar_entry(builder, "usr/b.conf", tar::EntryType::Regular, 0o644, b"same");
+            tar_entry(builder, "usr/c.conf", tar::EntryType::Regular, 0o644, b"other");
+            tar_entry(builder, "usr/.wh.d.conf", tar::EntryType::Regular, 0o644, b"");
+        });
+        let hash = |path: &str| {
+            let node = tree.get(path).unwrap();
+            node.content_hash().map(str::to_string)
+        };
+
+        assert_eq!(
+            hash("/etc/a.conf").as_deref(),
+            // sha256 of "same"
+            Some("sha256:0967115f2813a3541eaef77de9d9d5773f1c0c04314b0bbfe4ff3b3b1c55b5d5")
+        );
+        assert_eq!(hash("/etc/a.conf"), hash("/usr/b.conf"));
+        assert_ne!(hash("/etc/a.conf"), hash("/usr/c.conf"));
+        assert_eq!(hash("/usr/d.conf"), None);
+        assert_eq!(hash("/usr"), None);
+
+        let by_content = tree.files_by_content();
+        assert_eq!(by_content.len(), 2);
+        let mut same = by_content[&hash("/etc/a.conf").unwrap()].clone();
+        same.sort();
+        assert_eq!(same, vec![PathBuf::from("/etc/a.conf"), PathBuf::from("/usr/b.conf")]);
+    }
+
+    /// A PAX record, `<length> <key>=<value>\n` where the length includes itself
+    fn pax_record(key: &str, value: &[u8]) -> Vec<u8> {
+        let body_len = key.len() + value.len() + 3;
+        let mut len = body_len + 1;
+        while len != body_len + len.to_string().len() {
+            len = body_len + len.to_string().len();
+        }
+        let mut record = format!("{} {}=", len, key).into_bytes();
+        record.extend_from_slice(value);
+        record.push(b'\n');
+        record
+    }
+
+    #[test]
+    fn hardlinks_and_xattrs() {
+        let capability: Vec<u8> = [0x0200_0001u32, 1 << 13, 0, 0, 0]
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect();
+        let tree = archive_tree(|builder| {
+            let mut extensions = pax_record("SCHILY.xattr.security.capability", &capability);
+            extensions.extend(pax_record("SCHILY.xattr.user.comment", b"hello"));
+            tar_entry(builder, "PaxHeaders/ping", tar::EntryType::XHeader, 0o644, &extensions);
+            tar_entry(builder, "bin/ping", tar::EntryType::Regular, 0o755, b"12345678");
+
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Link);
+            header.set_mode(0o755);
+            header.set_size(0);
+            header.set_path("bin/ping6").unwrap();
+            header.set_link_name("bin/ping").unwrap();
+            header.set_cksum();
+            builder.append(&header, &[][..]).unwrap();
+            header.set_path("./bin/ping4").unwrap();
+            header.set_link_name("./bin/ping").unwrap();
+            header.set_cksum();
+            builder.append(&header, &[][..]).unwrap();
+        });
+
+        let node = |path: &str| tree.get(path).unwrap();
+        let ping = node("/bin/ping");
+        assert_eq!(ping.capabilities().as_deref(), Some("cap_net_raw=ep"));
+        assert_eq!(
+            ping.xattrs().keys().collect::<Vec<&String>>(),
+            vec!["security.capability", "user.comment"]
+        );
+        assert_eq!(
+            node("/bin/ping6").ftype(),
+            EntryType::Hardlink(PathBuf::from("/bin/ping"))
+        );
+        // Paths compare equal with a `.` inside, the name that is shown does not
+        assert_matches!(node("/bin/ping4").ftype(), EntryType::Hardlink(target) if target.as_os_str() == "/bin/ping");
+        assert!(node("/bin/ping6").xattrs().is_empty());
+        // The content is only counted once
+        assert_eq!(node("/bin").size(), 8);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn non_utf8_names() {
+        use std::os::unix::ffi::OsStrExt;
+        let path = |bytes: &[u8]| PathBuf::from(std::ffi::OsStr::from_bytes(bytes));
+
+        // Latin-1 names that would both become `caf\u{fffd}` when decoded lossily
+        let tree = archive_tree(|builder| {
+            tar_entry(builder, path(b"./srv/caf\xe9"), tar::EntryType::Regular, 0o644, b"e");
+            tar_entry(builder, path(b"./srv/caf\xe8"), tar::EntryType::Regular, 0o644, b"e");
+            tar_entry(builder, path(b"./srv/.wh.\xff"), tar::EntryType::Regular, 0o644, b"");
+        });
+        let srv = tree.get("/srv").unwrap();
+        let names: Vec<String> = srv.children().map(|node| node.display_name()).collect();
+        assert_eq!(names, ["caf\\xe8", "caf\\xe9", "\\xff"]);
+        assert_eq!(tree.get(path(b"/srv/caf\xe9")).unwrap().fop(), EntryOp::Add);
+        assert_eq!(tree.get(path(b"/srv/\xff")).unwrap().fop(), EntryOp::Remove);
+
+        // The cache keeps the names byte for byte
+        let serialised = serde_json::to_string(&tree).unwrap();
+        let deserialised: FileTree = serde_json::from_str(&serialised).unwrap();
+        assert_eq!(tree, deserialised);
+
+        // The filter matches the names as they are shown
+        let (filtered, err) = tree.filter_tree_full_path("srv/caf\\xe9");
+        assert_matches!(err, None);
+        assert_eq!(paths(&filtered), ["/srv", "/srv/caf\u{fffd}"]);
+    }
+
+    #[test]
+    fn special_permission_bits() {
+        assert_eq!(perm_str_from_u32(0o755), "rwxr-xr-x");
+        assert_eq!(perm_str_from_u32(0o2755), "rwxr-sr-x");
+        assert_eq!(perm_str_from_u32(0o4644), "rwSr--r--");
+        assert_eq!(perm_str_from_u32(0o1777), "rwxrwxrwt");
+        assert_eq!(perm_str_from_u32(0o1776), "rwxrwxrwT");
+    }
+
+    #[test]
+    fn tree_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<FileTree>();
+
+        // Trees can be built on other threads and handed back
+        let tree = std::thread::spawn(|| test_layer(&["usr/bin/ls", "etc/", "a"]))
+            .join()
+            .unwrap();
+        assert_eq!(tree.root().get_children_names(), ["a", "etc", "usr"]);
+
+        let ls = tree.get("/usr/bin/ls").unwrap();
+        let parents: Vec<String> = ls.ancestors().map(|node| node.display_name()).collect();
+        assert_eq!(parents, ["bin", "usr", "/"]);
+        assert_eq!(tree.root().child("etc").unwrap().path(), PathBuf::from("/etc"));
+        assert!(tree.root().child("var").is_none());
+    }
+
+    #[test]
+    fn symlink_resolution() {
+        let tree = archive_tree(|builder| {
+            tar_entry(builder, "./usr/lib/libssl.so.3", tar::EntryType::Regular, 0o644, b"ssl");
+            tar_entry(builder, "./usr/bin/", tar::EntryType::Directory, 0o755, b"");
+            let mut link = |path: &str, target: &str| {
+                let mut header = tar::Header::new_gnu();
+                header.set_entry_type(tar::EntryType::Symlink);
+                header.set_size(0);
+                builder.append_link(&mut header, path, target).unwrap();
+            };
+            link("./lib", "usr/lib");
+            link("./usr/lib/libssl.so", "libssl.so.3");
+            link("./usr/bin/ssl", "../../lib/libssl.so");
+            link("./usr/bin/absolute", "/lib/libssl.so");
+            link("./usr/bin/gone", "/opt/nothing");
+            link("./usr/bin/through-file", "../lib/libssl.so.3/more");
+            link("./usr/bin/up", "../../../../usr/lib/libssl.so.3");
+            link("./loop-a", "loop-b");
+            link("./loop-b", "/loop-a");
+        });
+        let libssl = tree.get("/usr/lib/libssl.so.3").unwrap().id();
+        assert_eq!(tree.resolve("/usr/bin/ssl"), LinkTarget::Resolved(libssl));
+        assert_eq!(tree.resolve("/usr/bin/absolute"), LinkTarget::Resolved(libssl));
+        assert_eq!(tree.resolve("/usr/bin/up"), LinkTarget::Resolved(libssl));
+        // Links in the middle of a path are followed as well
+        assert_eq!(tree.resolve("/lib/libssl.so"), LinkTarget::Resolved(libssl));
+        assert_eq!(
+            tree.resolve("/lib"),
+            LinkTarget::Resolved(tree.get("/usr/lib").unwrap().id())
+        );
+        assert_eq!(tree.resolve("/usr/bin/gone"), LinkTarget::Dangling);
+        assert_eq!(tree.resolve("/usr/bin/through-file"), LinkTarget::Dangling);
+        assert_eq!(tree.resolve("/loop-a"), LinkTarget::Loop);
+
+        // A later layer that removes the target leaves the link dangling
+        let upper = archive_tree(|builder| {
+            tar_entry(builder, "./usr/lib/.wh.libssl.so.3", tar::EntryType::Regular, 0o644, b"");
+        });
+        let merged = FileTree::merge(&[&tree, &upper]);
+        assert_eq!(merged.resolve("/usr/bin/ssl"), LinkTarget::Dangling);
+    }
+
+    #[test]
+    fn filter_matching_keeps_ancestors() {
+        let tree = test_layer(&[
+            "app/node_modules/left-pad/index.js",
+            "app/src/main.js",
+            "usr/lib/libc.so",
+            "usr/lib/libc.so.6",
+        ]);
+        let (filtered, matches) = tree
+            .filter_matching(|node| node.name() == "node_modules" || node.name() == "libc.so")
+            .unwrap();
+        assert_eq!(
+            paths(&filtered),
+            [
+                "/app",
+                "/app/node_modules",
+                "/app/node_modules/left-pad",
+                "/app/node_modules/left-pad/index.js",
+                "/usr",
+                "/usr/lib",
+                "/usr/lib/libc.so"
+            ]
+        );
+        assert_eq!(
+            matches,
+            [PathBuf::from("/app/node_modules"), PathBuf::from("/usr/lib/libc.so")]
+        );
+        // Sizes are the ones of the whole tree
+        assert_eq!(filtered.get("/usr").unwrap().size(), tree.get("/usr").unwrap().size());
+        assert!(tree.filter_matching(|node| node.name() == "nothing").is_none());
+    }
+
+    #[test]
+    fn lookup_by_path() {
+        let tree = test_layer(&["usr/lib/x86_64-linux-gnu/libssl.so.3", "usr/bin/ls"]);
+        let libssl = tree.get("/usr/lib/x86_64-linux-gnu/libssl.so.3").unwrap();
+        assert_eq!(libssl.name(), "libssl.so.3");
+        assert_eq!(libssl.parent().unwrap().path(), PathBuf::from("/usr/lib/x86_64-linux-gnu"));
+        // Relative paths are looked up from the root as well
+        assert_eq!(tree.get("usr/bin/ls").unwrap().id(), tree.get("/usr/bin/ls").unwrap().id());
+        assert_eq!(tree.get("/").unwrap().id(), tree.root().id());
+        assert!(tree.get("/usr/lib/libssl.so.3").is_none());
+        assert!(tree.get("/usr/bin/ls/more").is_none());
+    }
+
+    #[test]
+    fn traversal_orders() {
+        let tree = test_layer(&["a/b/c", "a/d", "e"]);
+        let names = |nodes: Vec<NodeRef>| -> Vec<String> {
+            nodes.iter().map(|node| node.display_name()).collect()
+        };
+        assert_eq!(names(tree.iter().collect()), ["/", "a", "e", "b", "d", "c"]);
+        assert_eq!(names(tree.iter_depth_first().collect()), ["/", "a", "b", "c", "d", "e"]);
+        assert_eq!(names(tree.iter_post_order().collect()), ["c", "b", "d", "a", "e", "/"]);
+    }
+
+    #[test]
+    fn walk_with_pruning() {
+        let tree = test_layer(&["a/b/c", "a/d", "e/f", "g"]);
+        let mut visited: Vec<String> = Vec::new();
+        tree.walk(|node| {
+            visited.push(node.display_name());
+            match node.display_name().as_str() {
+                "a" => WalkControl::SkipChildren,
+                "f" => WalkControl::Stop,
+                _ => WalkControl::Continue,
+            }
+        });
+        assert_eq!(visited, ["/", "a", "e", "f"]);
+    }
+
+    fn paths(tree: &FileTree) -> Vec<String> {
+        let mut paths: Vec<String> = tree
+            .iter()
+            .skip(1)
+            .map(|node| node.path().to_string_lossy().to_string())
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn merge_adds_and_removes() {
+        let base = test_layer(&["etc/passwd", "etc/hosts", "usr/bin/ls", "tmp/"]);
+        let upper = test_layer(&["etc/.wh.hosts", "usr/bin/cat", "home/user/"]);
+        let merged = FileTree::merge(&[&base, &upper]);
+        assert_eq!(
+            paths(&merged),
+            vec![
+                "/etc",
+                "/etc/passwd",
+                "/home",
+                "/home/user",
+                "/tmp",
+                "/usr",
+                "/usr/bin",
+                "/usr/bin/cat",
+                "/usr/bin/ls",
+            ]
+        );
+        // Layers themselves are left untouched
+        assert_eq!(paths(&base).len(), 7);
+        assert!(paths(&upper).contains(&"/etc/hosts".to_string()));
+    }
+
+    #[test]
+    fn merge_removes_whole_directories() {
+        let base = test_layer(&["opt/app/bin/app", "opt/app/lib/lib.so", "opt/other"]);
+        let upper = test_layer(&["opt/.wh.app"]);
+        let merged = FileTree::merge(&[&base, &upper]);
+        assert_eq!(paths(&merged), vec!["/opt", "/opt/other"]);
+    }
+
+    #[test]
+    fn merge_replaces_file_with_directory() {
+        let base = test_layer(&["data", "keep"]);
+        let upper = test_layer(&["data/file"]);
+        let merged = FileTree::merge(&[&base, &upper]);
+        assert_eq!(paths(&merged), vec!["/data", "/data/file", "/keep"]);
+        let data = merged.get("/data").unwrap();
+        assert_eq!(data.ftype(), EntryType::Directory);
+
+        let top = test_layer(&["data"]);
+        let merged = FileTree::merge(&[&base, &upper, &top]);
+        assert_eq!(paths(&merged), vec!["/data", "/keep"]);
+        let data = merged.get("/data").unwrap();
+        assert_eq!(data.ftype(), EntryType::File);
+    }
+
+    #[test]
+    fn merge_ignores_whiteouts_in_new_directories() {
+        let base = test_layer(&["etc/"]);
+        let upper = test_layer(&["srv/.wh.nothing", "srv/file"]);
+        let merged = FileTree::merge(&[&base, &upper]);
+        assert_eq!(paths(&merged), vec!["/etc", "/srv", "/srv/file"]);
+    }
+
+    #[test]
+    fn opaque_directory() {
+        let layer = test_layer(&["etc/app/.wh..wh..opq", "etc/app/new.conf", "etc/other"]);
+        let app = layer.get("/etc/app").unwrap();
+        assert!(app.is_opaque());
+        assert_eq!(app.get_children_names(), vec!["new.conf"]);
+        let etc = layer.get("/etc").unwrap();
+        assert!(!etc.is_opaque());
+    }
+
+    #[test]
+    fn merge_opaque_directory() {
+        let base = test_layer(&["etc/app/old.conf", "etc/app/sub/file", "etc/keep"]);
+        let upper = test_layer(&["etc/app/.wh..wh..opq", "etc/app/new.conf"]);
+        let merged = FileTree::merge(&[&base, &upper]);
+        assert_eq!(
+            paths(&merged),
+            vec!["/etc", "/etc/app", "/etc/app/new.conf", "/etc/keep"]
+        );
+        let app = merged.get("/etc/app").unwrap();
+        assert!(!app.is_opaque());
+    }
+
+    #[test]
+    fn classify_modified_entries() {
+        let base = test_layer(&["etc/passwd", "etc/hosts", "usr/bin/"]);
+        let mut upper = test_layer(&["etc/passwd", "etc/.wh.hosts", "etc/shadow", "usr/bin/ls"]);
+        // A chmod of a directory is a change of its own
+        let etc = upper.get("/etc").unwrap().id();
+        upper.nodes[etc].data.mode ^= 0o002;
+        let classified = upper.classify_against(&FileTree::merge(&[&base]));
+
+        let fop = |path: &str| classified.get(path).unwrap().fop();
+        assert_eq!(fop("/etc"), EntryOp::Modify);
+        assert_eq!(fop("/etc/passwd"), EntryOp::Modify);
+        assert_eq!(fop("/etc/hosts"), EntryOp::Remove);
+        assert_eq!(fop("/etc/shadow"), EntryOp::Add);
+        assert_eq!(fop("/usr"), EntryOp::Add);
+        assert_eq!(fop("/usr/bin"), EntryOp::Add);
+        assert_eq!(fop("/usr/bin/ls"), EntryOp::Add);
+
+        assert_eq!(
+            classified.op_counts(),
+            OpCounts {
+                added: 4,
+                modified: 2,
+                removed: 1
+            }
+        );
+        // The layer itself is not changed
+        assert_eq!(upper.op_counts().modified, 0);
+    }
+
+    #[test]
+    fn match_nodes_by_path() {
+        let layer = test_layer(&["etc/passwd", "usr/bin/ls", "tmp/"]);
+        let other = test_layer(&["usr/bin/ls", "etc/hosts"]);
+        let matched = layer.match_nodes(&other);
+
+        let id = |tree: &FileTree, path: &str| tree.get(path).unwrap().id();
+        assert_eq!(matched[0], Some(0));
+        assert_eq!(matched[id(&layer, "/usr/bin/ls")], Some(id(&other, "/usr/bin/ls")));
+        assert_eq!(matched[id(&layer, "/etc")], Some(id(&other, "/etc")));
+        assert_eq!(matched[id(&layer, "/etc/passwd")], None);
+        assert_eq!(matched[id(&layer, "/tmp")], None);
+    }
+
+    #[test]
+    fn diff_of_two_filesystems() {
+        let old = test_layer(&["etc/passwd", "etc/hosts", "usr/bin/ls", "tmp/cache/a", "tmp/cache/b"]);
+        let mut new = test_layer(&["etc/passwd", "etc/hosts", "usr/bin/ls", "usr/bin/cat", "tmp/"]);
+        let passwd = new.get("/etc/passwd").unwrap().id();
+        new.nodes[passwd].data.size += 10;
+        new.nodes[passwd].data.content_hash = None;
+        let hosts = new.get("/etc/hosts").unwrap().id();
+        new.nodes[hosts].data.mode = 0o600;
+
+        let diff = old.diff(&new);
+        assert_eq!(
+            paths(&diff.tree),
+            vec![
+                "/etc",
+                "/etc/hosts",
+                "/etc/passwd",
+                "/tmp",
+                "/tmp/cache",
+                "/tmp/cache/a",
+                "/tmp/cache/b",
+                "/usr",
+                "/usr/bin",
+                "/usr/bin/cat",
+            ]
+        );
+        let entry = |path: &str| diff.entry(Path::new(path)).unwrap();
+        assert_eq!(entry("/etc/passwd").change, Some(PathChange::Modified));
+        assert_eq!(entry("/etc/passwd").size_delta, 10);
+        assert_eq!(entry("/etc/hosts").change, Some(PathChange::Metadata));
+        assert_eq!(entry("/etc").change, None);
+        assert_eq!(entry("/usr/bin/cat").change, Some(PathChange::Added));
+        assert_eq!(entry("/tmp/cache").change, Some(PathChange::Removed));
+        assert_eq!(entry("/tmp/cache").size_delta, -("tmp/cache/a".len() as i64 * 2));
+        assert_eq!(diff.tree.get("/tmp/cache/b").unwrap().fop(), EntryOp::Remove);
+        assert_eq!(diff.size_delta(), "usr/bin/cat".len() as i64 - 2 * "tmp/cache/a".len() as i64);
+        assert_eq!(diff.counts()[&PathChange::Removed], 3);
+
+        assert_eq!(old.diff(&old).tree.len(), 1);
+    }
+
+    #[test]
+    fn directory_sizes() {
+        let layer = test_layer(&["usr/bin/ls", "usr/lib/libc.so", "etc/"]);
+        let size = |tree: &FileTree, path: &str| tree.get(path).unwrap().size();
+        // layer_from_paths writes the path into each file
+        assert_eq!(size(&layer, "/usr/bin"), "usr/bin/ls".len() as u64);
+        assert_eq!(size(&layer, "/usr"), ("usr/bin/ls".len() + "usr/lib/libc.so".len()) as u64);
+        assert_eq!(size(&layer, "/etc"), 0);
+        assert_eq!(layer.root().size(), size(&layer, "/usr"));
+
+        // Merged directories count the contents of every layer and skip removed entries
+        let upper = test_layer(&["usr/lib/.wh.libc.so", "usr/bin/cat"]);
+        let merged = FileTree::merge(&[&layer, &upper]);
+        assert_eq!(size(&merged, "/usr"), ("usr/bin/ls".len() + "usr/bin/cat".len()) as u64);
+    }
+
+    #[test]
+    fn human_readable_sizes() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1536), "1.5 KB");
+        assert_eq!(format_size(10 * 1024 * 1024), "10.0 MB");
+        assert_eq!(format_size(3 * 1024 * 1024 * 1024), "3.0 GB");
+        assert_eq!(format_size_delta(1536), "+1.5 KB");
+        assert_eq!(format_size_delta(-12), "-12 B");
     }
 
     #[test]
@@ -649,6 +2323,6 @@ mod tests {
         let layer_tree: FileTree = serde_json::from_reader(layer_cache_file).unwrap();
         let (filtered_tree, error) = layer_tree.filter_tree_full_path("asdsadsasaddsaads");
         assert_matches!(error, Some(GUIError::CantFilterTree));
-        assert_eq!(layer_tree, filtered_tree);
+        assert_eq!(layer_tree, *filtered_tree);
     }
 }
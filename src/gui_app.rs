@@ -1,27 +1,39 @@
 use crate::docker_image_utils::ImageRepr;
+use crate::efficiency::Efficiency;
+use crate::widgets::details_widget::DetailsWidget;
+use crate::widgets::efficiency_widget::EfficiencyWidget;
 use crate::widgets::focus_traits::WidgetFocusTrait;
 use crate::widgets::help_widget;
+use crate::file_tree::{EntryType, FileTree, LinkTarget, OpCounts, TreeDiff};
+use crate::history::{Blame, path_history};
+use crate::search::search_layers;
 use crate::widgets::multitree_browser_widget::{
-    MultiTreeBrowserWidget, MultiTreeBrowserWidgetState,
+    MultiTreeBrowserWidget, MultiTreeBrowserWidgetState, TreeViewMode,
 };
 use crate::widgets::navigation_traits::{WidgetNav, WidgetNavBounds};
 use crate::widgets::searchbar::{SearchBarWidget, SearchBarWidgetState};
+use crate::widgets::tree_browser_widget::Annotations;
 use crossterm::event::{self, Event, KeyCode, KeyEvent};
 use ratatui::{
     DefaultTerminal, Frame,
     layout::{Constraint, Direction, Layout},
-    widgets::ListState,
 };
+use std::collections::HashMap;
 use std::io;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
 use crate::widgets::layer_browser_widget::{LayerBrowserWidget, LayerBrowserWidgetState};
+use crate::widgets::layer_search_widget::{LayerSearchWidget, LayerSearchWidgetState};
+use crate::widgets::path_history_widget::{PathHistoryWidget, PathHistoryWidgetState};
 
 #[derive(Clone, Copy)]
 enum Focus {
     List,
     Tree,
     SearchBar,
+    LayerSearch,
+    PathHistory,
     Help,
 }
 
@@ -32,9 +44,22 @@ pub struct App {
     list_state: LayerBrowserWidgetState,
     layer_names: Vec<String>,
     layer_commands: Vec<String>,
+    layer_failed: Vec<bool>,
+    layer_op_counts: Vec<OpCounts>,
+    // Layer index and path of every file by content hash
+    content_index: HashMap<String, Vec<(usize, PathBuf)>>,
+    efficiency: Efficiency,
+    // Merged filesystems by layer index, computed when first shown
+    aggregated_trees: HashMap<usize, FileTree>,
+    // Final filesystem with the owner of every entry, computed when first shown
+    blame: Option<Blame>,
+    // Diff of the merged filesystems of the marked layers, lower layer first
+    diff: Option<((usize, usize), TreeDiff)>,
     focus: Focus,
     prev_focus: Focus,
     search_bar_state: SearchBarWidgetState,
+    layer_search_state: LayerSearchWidgetState,
+    path_history_state: PathHistoryWidgetState,
 }
 
 impl App {
@@ -43,33 +68,48 @@ impl App {
         let layer_commands: Vec<String> = item
             .layers
             .iter()
-            .map(|layer| layer.command.clone())
+            .map(|layer| {
+                let mut command = layer.command.clone();
+                if let Some(created) = &layer.created {
+                    command = format!("{}\n\nCreated: {}", command, created);
+                }
+                if let Some(error) = &layer.error {
+                    command = format!("{}\n\nFailed to load: {}", command, error);
+                }
+                command
+            })
             .collect();
+        let layer_failed: Vec<bool> = item.layers.iter().map(|layer| layer.error.is_some()).collect();
+        let layer_op_counts: Vec<OpCounts> =
+            item.layers.iter().map(|layer| layer.tree.op_counts()).collect();
 
-        if layer_names.len() == 0 {
-            panic!("No layers found in image");
-        }
-        if layer_names.len() != layer_commands.len() {
-            panic!("Layer names and commands are not the same length");
-        }
-
-        let mut list_state = ListState::default();
-        list_state.select(Some(0));
+        let content_index = item.content_index();
+        let trees: Vec<&FileTree> = item.layers.iter().map(|layer| &layer.tree).collect();
+        let efficiency = Efficiency::analyze(&trees);
 
         let mut app = App {
             item,
             exit: false,
-            layer_names: layer_names.clone(),
+            layer_names,
             layer_commands,
+            layer_failed,
+            layer_op_counts,
+            content_index,
+            efficiency,
+            aggregated_trees: HashMap::new(),
+            blame: None,
+            diff: None,
             list_state: LayerBrowserWidgetState::new(),
             focus: Focus::List,
             prev_focus: Focus::Tree,
-            tree_state: MultiTreeBrowserWidgetState::new("", &layer_names[..]),
+            tree_state: MultiTreeBrowserWidgetState::new(""),
             search_bar_state: SearchBarWidgetState::new(),
+            layer_search_state: LayerSearchWidgetState::new(),
+            path_history_state: PathHistoryWidgetState::new(),
         };
 
         app.adjust_tree_state_to_list();
-        return app;
+        app
     }
 
     fn layer_names_from_img(img: &ImageRepr) -> Vec<String> {
@@ -91,6 +131,8 @@ impl App {
                 self.change_focus(Focus::List);
             }
             Focus::SearchBar => {}
+            Focus::LayerSearch => {}
+            Focus::PathHistory => {}
             Focus::Help => {}
         }
     }
@@ -100,13 +142,156 @@ impl App {
         if selected >= self.layer_names.len() {
             return;
         }
-        let selected_layer = &self.layer_names[selected];
-        self.tree_state.current_layer = selected_layer.to_string();
+        self.tree_state.current_layer = selected;
+        // Needed in both view modes, symlinks are resolved in the merged filesystem
+        self.aggregated_trees
+            .entry(selected)
+            .or_insert_with(|| self.item.aggregated_tree(selected));
+        if self.tree_state.view_mode == TreeViewMode::Blame && self.blame.is_none() {
+            let trees: Vec<&FileTree> = self.item.layers.iter().map(|layer| &layer.tree).collect();
+            self.blame = Some(Blame::new(&trees));
+        }
+        if self.tree_state.view_mode == TreeViewMode::Diff {
+            self.adjust_diff_to_marks();
+        }
+    }
+
+    fn adjust_diff_to_marks(&mut self) {
+        let Some((lower, upper)) = self.list_state.marked_pair() else {
+            // Nothing to compare anymore
+            self.diff = None;
+            self.tree_state.view_mode = TreeViewMode::LayerChanges;
+            self.tree_state.forget(TreeViewMode::Diff);
+            return;
+        };
+        if self.diff.as_ref().is_some_and(|(pair, _)| *pair == (lower, upper)) {
+            return;
+        }
+        for layer in [lower, upper] {
+            self.aggregated_trees
+                .entry(layer)
+                .or_insert_with(|| self.item.aggregated_tree(layer));
+        }
+        let diff = self.aggregated_trees[&lower].diff(&self.aggregated_trees[&upper]);
+        self.diff = Some(((lower, upper), diff));
+        self.tree_state.forget(TreeViewMode::Diff);
+    }
+
+    /// Shows the diff of the two marked layers, or leaves it
+    fn toggle_diff(&mut self) {
+        if self.tree_state.view_mode == TreeViewMode::Diff {
+            self.tree_state.view_mode = TreeViewMode::LayerChanges;
+        } else if self.list_state.marked_pair().is_some() {
+            self.tree_state.view_mode = TreeViewMode::Diff;
+        }
+        self.adjust_tree_state_to_list();
+    }
+
+    fn toggle_mark(&mut self) {
+        self.list_state.toggle_mark();
+        self.adjust_tree_state_to_list();
+    }
+
+    /// The merged filesystem the symlinks of the shown tree are resolved in
+    fn filesystem(&self) -> Option<&FileTree> {
+        match self.tree_state.view_mode {
+            TreeViewMode::Blame => self.blame.as_ref().map(|blame| &blame.tree),
+            TreeViewMode::Diff => self
+                .diff
+                .as_ref()
+                .and_then(|((_, upper), _)| self.aggregated_trees.get(upper)),
+            _ => self.aggregated_trees.get(&self.tree_state.current_layer),
+        }
+    }
+
+    /// Selects the entry the selected symlink points to. If the layer does not change the
+    /// target, the view switches to the merged filesystem, where it can be found.
+    fn jump_to_link_target(&mut self) {
+        let layer = self.tree_state.current_layer;
+        let Some(filesystem) = self.filesystem() else {
+            return;
+        };
+        let Some(link) = self.tree_state.selected_path() else {
+            return;
+        };
+        if !matches!(filesystem.get(&link).map(|node| node.ftype()), Some(EntryType::Symlink(_))) {
+            return;
+        }
+        let LinkTarget::Resolved(target) = filesystem.resolve(&link) else {
+            return;
+        };
+        let target = filesystem.node(target).path();
+
+        let in_layer = self.item.layers[layer].tree.get(&target).is_some();
+        if self.tree_state.view_mode == TreeViewMode::LayerChanges && !in_layer {
+            self.toggle_view_mode();
+        }
+        self.tree_state.select_path(&target);
+    }
+
+    fn toggle_view_mode(&mut self) {
+        self.tree_state.view_mode = self.tree_state.view_mode.toggled();
+        self.adjust_tree_state_to_list();
     }
 
     fn adjust_tree_state_to_search_bar_content(&mut self) {
         let search_string = self.search_bar_state.get();
-        self.tree_state.set_search_string(&search_string);
+        let mode = self.search_bar_state.mode();
+        self.tree_state.set_search(&search_string, mode);
+    }
+
+    fn open_layer_search(&mut self) {
+        let search_string = self.search_bar_state.get();
+        self.layer_search_state.reset(&search_string, self.search_bar_state.mode());
+        self.adjust_layer_search_results();
+        self.change_focus(Focus::LayerSearch);
+    }
+
+    fn adjust_layer_search_results(&mut self) {
+        let trees = self.item.layers.iter().map(|layer| &layer.tree);
+        let search_string = self.layer_search_state.get();
+        let results = search_layers(trees, self.layer_search_state.mode(), &search_string);
+        self.layer_search_state.set_results(results);
+    }
+
+    /// Shows the changes of the layer of the selected search result with its entry selected.
+    /// The tree is filtered with the same search, so that the entry is not hidden by the
+    /// previous one.
+    fn jump_to_layer_search_result(&mut self) {
+        let Some(found) = self.layer_search_state.selected().cloned() else {
+            return;
+        };
+        self.search_bar_state
+            .set(&self.layer_search_state.get(), self.layer_search_state.mode());
+        self.adjust_tree_state_to_search_bar_content();
+        self.show_layer_entry(found.layer, &found.path);
+    }
+
+    /// Shows the changes of a layer with the entry at `path` selected
+    fn show_layer_entry(&mut self, layer: usize, path: &Path) {
+        self.list_state.select(Some(layer));
+        // The other views show more than the layer, or not the layer at all
+        self.tree_state.view_mode = TreeViewMode::LayerChanges;
+        self.adjust_tree_state_to_list();
+        self.tree_state.select_path(path);
+        self.change_focus(Focus::Tree);
+    }
+
+    fn open_path_history(&mut self) {
+        let Some(path) = self.tree_state.selected_path() else {
+            return;
+        };
+        let history = path_history(self.item.layers.iter().map(|layer| &layer.tree), &path);
+        self.path_history_state.set(path, history);
+        self.change_focus(Focus::PathHistory);
+    }
+
+    fn jump_to_path_history_entry(&mut self) {
+        let Some(layer) = self.path_history_state.selected().map(|entry| entry.layer) else {
+            return;
+        };
+        let path = self.path_history_state.path().clone();
+        self.show_layer_entry(layer, &path);
     }
 
     fn next(&mut self) {
@@ -119,6 +304,8 @@ impl App {
                 self.tree_state.next();
             }
             Focus::SearchBar => {}
+            Focus::LayerSearch => {}
+            Focus::PathHistory => {}
             Focus::Help => {}
         }
     }
@@ -133,6 +320,8 @@ impl App {
                 self.tree_state.prev();
             }
             Focus::SearchBar => {}
+            Focus::LayerSearch => {}
+            Focus::PathHistory => {}
             Focus::Help => {}
         }
     }
@@ -147,12 +336,9 @@ impl App {
 
     fn render(&mut self, frame: &mut Frame) {
         let area = frame.area();
-        match self.focus {
-            Focus::Help => {
-                help_widget::draw_help(frame, area);
-                return;
-            }
-            _ => {}
+        if matches!(self.focus, Focus::Help) {
+            help_widget::draw_help(frame, area);
+            return;
         }
         let vlayout = Layout::default()
             .direction(Direction::Vertical)
@@ -164,16 +350,100 @@ impl App {
             .constraints([Constraint::Percentage(50), Constraint::Percentage(50)].as_ref())
             .split(vlayout[0]);
 
-        let layers_and_commands = LayerBrowserWidget::new(&self.layer_names, &self.layer_commands);
+        let layers_and_commands = LayerBrowserWidget::new(
+            &self.layer_names,
+            &self.layer_commands,
+            &self.layer_failed,
+            &self.layer_op_counts,
+        );
+
+        let layers_layout = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Fill(100), Constraint::Length(10)].as_ref())
+            .split(hlayout[0]);
 
         layers_and_commands.ensure_bounds(&mut self.list_state);
-        frame.render_stateful_widget(layers_and_commands, hlayout[0], &mut self.list_state);
+        frame.render_stateful_widget(layers_and_commands, layers_layout[0], &mut self.list_state);
+        frame.render_widget(EfficiencyWidget::new(&self.efficiency), layers_layout[1]);
 
-        let tree_widget = MultiTreeBrowserWidget::new(&self.item.layers[..]);
-        frame.render_stateful_widget(tree_widget, hlayout[1], &mut self.tree_state);
+        let trees: HashMap<usize, &FileTree> = match self.tree_state.view_mode {
+            TreeViewMode::LayerChanges => self
+                .item
+                .layers
+                .iter()
+                .enumerate()
+                .map(|(i, layer)| (i, &layer.tree))
+                .collect(),
+            TreeViewMode::Aggregated => self
+                .aggregated_trees
+                .iter()
+                .map(|(&i, tree)| (i, tree))
+                .collect(),
+            TreeViewMode::Blame => self
+                .blame
+                .iter()
+                .map(|blame| (self.tree_state.current_layer, &blame.tree))
+                .collect(),
+            TreeViewMode::Diff => self
+                .diff
+                .iter()
+                .map(|(_, diff)| (self.tree_state.current_layer, &diff.tree))
+                .collect(),
+        };
+        let tree_layout = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Fill(100), Constraint::Length(11)].as_ref())
+            .split(hlayout[1]);
+
+        let selected_path = self.tree_state.selected_path();
+        let selected_node = trees
+            .get(&self.tree_state.current_layer)
+            .zip(selected_path.as_ref())
+            .and_then(|(tree, path)| tree.get(path));
+        // Other paths with the same content, in any layer
+        let copies: Vec<(usize, PathBuf)> = selected_node
+            .as_ref()
+            .and_then(|node| node.content_hash().map(str::to_string))
+            .and_then(|hash| self.content_index.get(&hash))
+            .map(|places| {
+                places
+                    .iter()
+                    .filter(|(_, path)| Some(path) != selected_path.as_ref())
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        frame.render_widget(DetailsWidget::new(selected_node, copies), tree_layout[1]);
 
-        let search = SearchBarWidget::new();
+        // Borrows the fields one by one, the tree state is borrowed mutably below
+        let (filesystem, annotations) = match (self.tree_state.view_mode, &self.blame, &self.diff) {
+            (TreeViewMode::Blame, Some(blame), _) => {
+                (Some(&blame.tree), Annotations::Blame(blame, &self.item.layers))
+            }
+            (TreeViewMode::Diff, _, Some(((_, upper), diff))) => {
+                (self.aggregated_trees.get(upper), Annotations::Diff(diff))
+            }
+            _ => (self.aggregated_trees.get(&self.tree_state.current_layer), Annotations::None),
+        };
+        let tree_widget = MultiTreeBrowserWidget::new(trees, filesystem, annotations);
+        frame.render_stateful_widget(tree_widget, tree_layout[0], &mut self.tree_state);
+
+        // The tree compiled the search string while it filtered itself
+        self.search_bar_state.set_error(self.tree_state.pattern_error());
+        let search = SearchBarWidget::new(self.tree_state.search_failed());
         frame.render_stateful_widget(search, vlayout[1], &mut self.search_bar_state);
+
+        match self.focus {
+            Focus::LayerSearch => {
+                let layer_search = LayerSearchWidget::new(&self.layer_names);
+                frame.render_stateful_widget(layer_search, area, &mut self.layer_search_state);
+            }
+            Focus::PathHistory => {
+                let history = PathHistoryWidget::new(&self.item.layers);
+                frame.render_stateful_widget(history, area, &mut self.path_history_state);
+            }
+            _ => {}
+        }
     }
 
     fn get_all_key_events() -> Vec<KeyEvent> {
@@ -207,6 +477,8 @@ impl App {
             Focus::List => self.list_state.focus_on(true),
             Focus::Tree => self.tree_state.focus_on(true),
             Focus::SearchBar => self.search_bar_state.focus_on(true),
+            Focus::LayerSearch => {}
+            Focus::PathHistory => {}
             Focus::Help => {}
         }
 
@@ -216,6 +488,15 @@ impl App {
     fn handle_events(&mut self) -> io::Result<()> {
         let key_events = App::get_all_key_events();
         for key_event in key_events {
+            // Raw mode turns Ctrl+C into a key press instead of SIGINT, so it is handled here
+            if key_event.code == KeyCode::Char('c')
+                && key_event
+                    .modifiers
+                    .contains(crossterm::event::KeyModifiers::CONTROL)
+            {
+                self.exit = true;
+                continue;
+            }
             match self.focus {
                 Focus::SearchBar => match key_event.code {
                     KeyCode::Char(c) => {
@@ -226,23 +507,58 @@ impl App {
                         self.search_bar_state.pop_c();
                         self.adjust_tree_state_to_search_bar_content();
                     }
+                    KeyCode::Tab => {
+                        self.search_bar_state.next_mode();
+                        self.adjust_tree_state_to_search_bar_content();
+                    }
                     KeyCode::Esc | KeyCode::Enter => {
                         self.change_focus(self.prev_focus);
                     }
                     _ => {}
                 },
-                Focus::Help => match key_event.code {
-                    KeyCode::Char('q') => {
-                        self.change_focus(self.prev_focus);
+                Focus::LayerSearch => match key_event.code {
+                    KeyCode::Char(c) => {
+                        self.layer_search_state.push_c(c);
+                        self.adjust_layer_search_results();
+                    }
+                    KeyCode::Backspace => {
+                        self.layer_search_state.pop_c();
+                        self.adjust_layer_search_results();
+                    }
+                    KeyCode::Tab => {
+                        self.layer_search_state.next_mode();
+                        self.adjust_layer_search_results();
                     }
+                    KeyCode::Down => self.layer_search_state.next(),
+                    KeyCode::Up => self.layer_search_state.prev(),
+                    KeyCode::Enter => self.jump_to_layer_search_result(),
+                    KeyCode::Esc => self.change_focus(self.prev_focus),
                     _ => {}
                 },
+                Focus::PathHistory => match key_event.code {
+                    KeyCode::Down => self.path_history_state.next(),
+                    KeyCode::Up => self.path_history_state.prev(),
+                    KeyCode::Enter => self.jump_to_path_history_entry(),
+                    KeyCode::Esc | KeyCode::Char('q') => self.change_focus(self.prev_focus),
+                    _ => {}
+                },
+                Focus::Help => {
+                    if key_event.code == KeyCode::Char('q') {
+                        self.change_focus(self.prev_focus);
+                    }
+                }
                 _ => {
                     match key_event.code {
                         KeyCode::Down => self.next(),                   // Move selection down
                         KeyCode::Up => self.previous(),                 // Move selection up
                         KeyCode::Tab => self.circle_focus(), // Switch between list and tree
                         KeyCode::Char(' ') => self.tree_state.expand(), // Expand tree
+                        KeyCode::Char('a') => self.toggle_view_mode(), // Layer changes or merged fs
+                        KeyCode::Char('m') => self.toggle_mark(), // Mark a layer for a diff
+                        KeyCode::Char('d') => self.toggle_diff(), // Diff of the marked layers
+                        KeyCode::Char('g') => self.jump_to_link_target(), // Follow a symlink
+                        KeyCode::Char('l') => self.open_layer_search(), // Search all layers
+                        KeyCode::Char('H') => self.open_path_history(), // Layers that touched the path
                         KeyCode::Char('q') => self.exit = true, // Quit
                         KeyCode::Char('h') => {
                             self.change_focus(Focus::Help);
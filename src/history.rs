@@ -0,0 +1,151 @@
+use crate::file_tree::{EntryOp, FileTree, FileTreeNodeData, PathChange};
+use std::path::Path;
+
+/// A layer that touched the path, with the entry as the layer left it
+#[derive(Clone, Debug)]
+pub struct HistoryEntry {
+    pub layer: usize,
+    pub change: PathChange,
+    /// None when the layer removed the path
+    pub data: Option<FileTreeNodeData>,
+}
+
+/// Every layer that added, changed or removed the entry at `path`, in layer order
+pub fn path_history<'a>(layers: impl IntoIterator<Item = &'a FileTree>, path: &Path) -> Vec<HistoryEntry> {
+    let mut history = Vec::new();
+    // The entry as the layers so far left it
+    let mut current: Option<FileTreeNodeData> = None;
+    for (layer, tree) in layers.into_iter().enumerate() {
+        let change = match tree.get(path) {
+            Some(node) if node.fop() == EntryOp::Remove => {
+                current = None;
+                Some((PathChange::Removed, None))
+            }
+            Some(node) => {
+                let data = FileTreeNodeData::clone(&node);
+                // Directories are in every layer that writes below them and files may be
+                // written again as they were; neither changes the entry
+                let change = match &current {
+                    None => Some(PathChange::Added),
+                    Some(previous) => data.change_from(previous),
+                };
+                current = Some(data.clone());
+                change.map(|change| (change, Some(data)))
+            }
+            None if current.is_some() && hidden_by_ancestor(tree, path) => {
+                current = None;
+                Some((PathChange::Removed, None))
+            }
+            None => None,
+        };
+        if let Some((change, data)) = change {
+            history.push(HistoryEntry { layer, change, data });
+        }
+    }
+    history
+}
+
+/// Whether the layer writes or removes the entry at `path`, even if it leaves it as it was
+pub fn touches(tree: &FileTree, path: &Path) -> bool {
+    tree.get(path).is_some() || hidden_by_ancestor(tree, path)
+}
+
+/// Whether the layer removes a directory above `path` or replaces one with an opaque directory
+fn hidden_by_ancestor(tree: &FileTree, path: &Path) -> bool {
+    path.ancestors()
+        .skip(1)
+        .filter_map(|ancestor| tree.get(ancestor))
+        .any(|ancestor| ancestor.id() != 0 && (ancestor.fop() == EntryOp::Remove || ancestor.is_opaque()))
+}
+
+/// The merged filesystem of all layers, with the layer that last wrote each of its entries
+pub struct Blame {
+    pub tree: FileTree,
+    // Layer index by node id of the tree; the root belongs to no layer
+    owners: Vec<Option<usize>>,
+}
+
+impl Blame {
+    pub fn new(layers: &[&FileTree]) -> Blame {
+        let tree = FileTree::merge(layers);
+        let mut owners = vec![None; tree.len()];
+        for (index, layer) in layers.iter().enumerate() {
+            let merged_nodes = layer.match_nodes(&tree);
+            for node in layer.iter().skip(1) {
+                if let (Some(merged), false) = (merged_nodes[node.id()], node.fop() == EntryOp::Remove) {
+                    owners[merged] = Some(index);
+                }
+            }
+        }
+        Blame { tree, owners }
+    }
+
+    /// Index of the layer that last wrote the entry at `path`
+    pub fn owner(&self, path: &Path) -> Option<usize> {
+        self.tree.get(path).and_then(|node| self.owners[node.id()])
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::file_tree::test_layer;
+
+    #[test]
+    fn history_of_a_file() {
+        let added = test_layer(&[("etc/app.conf", "one", 0o644)]);
+        let unrelated = test_layer(&[("etc/hosts", "localhost", 0o644)]);
+        let modified = test_layer(&[("etc/app.conf", "two", 0o644)]);
+        let chmoded = test_layer(&[("etc/app.conf", "two", 0o600)]);
+        let removed = test_layer(&[("etc/.wh.app.conf", "", 0o644)]);
+        let readded = test_layer(&[("etc/app.conf", "three", 0o644)]);
+        let parent_removed = test_layer(&[(".wh.etc", "", 0o644)]);
+
+        let layers = [&added, &unrelated, &modified, &chmoded, &removed, &readded, &parent_removed];
+        let history = path_history(layers, Path::new("/etc/app.conf"));
+        let changes: Vec<(usize, PathChange)> = history.iter().map(|entry| (entry.layer, entry.change)).collect();
+        assert_eq!(
+            changes,
+            [
+                (0, PathChange::Added),
+                (2, PathChange::Modified),
+                (3, PathChange::Metadata),
+                (4, PathChange::Removed),
+                (5, PathChange::Added),
+                (6, PathChange::Removed),
+            ]
+        );
+        assert_eq!(history[1].data.as_ref().map(|data| data.size()), Some(3));
+        assert_eq!(history[2].data.as_ref().map(|data| data.mode()), Some(0o600));
+        assert!(history[3].data.is_none());
+    }
+
+    #[test]
+    fn unchanged_entries_are_not_history() {
+        let base = test_layer(&[("etc/app.conf", "one")]);
+        let child_written = test_layer(&[("etc/hosts", "localhost")]);
+        let rewritten = test_layer(&[("etc/app.conf", "one")]);
+
+        let layers = [&base, &child_written, &rewritten];
+        let changes = |path: &str| -> Vec<(usize, PathChange)> {
+            path_history(layers, Path::new(path)).iter().map(|entry| (entry.layer, entry.change)).collect()
+        };
+        assert_eq!(changes("/etc"), [(0, PathChange::Added)]);
+        assert_eq!(changes("/etc/app.conf"), [(0, PathChange::Added)]);
+    }
+
+    #[test]
+    fn blame() {
+        let base = test_layer(&[("etc/app.conf", "one"), ("usr/bin/app", "v1")]);
+        let update = test_layer(&[("usr/bin/app", "v2"), ("tmp/build.log", "")]);
+        let cleanup = test_layer(&["tmp/.wh.build.log"]);
+
+        let blame = Blame::new(&[&base, &update, &cleanup]);
+        assert_eq!(blame.owner(Path::new("/etc/app.conf")), Some(0));
+        assert_eq!(blame.owner(Path::new("/usr/bin/app")), Some(1));
+        // Directories belong to the last layer that had anything in them
+        assert_eq!(blame.owner(Path::new("/tmp")), Some(2));
+        assert!(blame.tree.get("/tmp/build.log").is_none());
+        assert_eq!(blame.owner(Path::new("/")), None);
+    }
+}
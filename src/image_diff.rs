@@ -0,0 +1,228 @@
+use crate::docker_image_utils::{ImageLayer, ImageRepr};
+use crate::file_names::escape_name;
+use crate::file_tree::{EntryType, PathChange, TreeDiff, WalkControl, format_size_delta};
+use std::io::{self, Write};
+
+/// Where a layer of one image ends up in the other one
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LayerAlignment {
+    /// The same layer at the same position; images share a prefix of such layers
+    Shared { old: usize, new: usize },
+    /// Made by the same command after the shared prefix; the content may still be the same
+    Rebuilt { old: usize, new: usize, same_content: bool },
+    /// Only in the new image
+    New(usize),
+    /// Only in the old image
+    Dropped(usize),
+}
+
+/// Lines up the layers of two images. Layers are shared as long as both images have the
+/// same ones from the bottom; after that, the layers made by the same commands are paired
+/// in order and everything else is new or dropped.
+pub fn align_layers(old: &[ImageLayer], new: &[ImageLayer]) -> Vec<LayerAlignment> {
+    let shared = old
+        .iter()
+        .zip(new.iter())
+        .take_while(|(old, new)| old.name == new.name && old.error.is_none())
+        .count();
+    let mut alignment: Vec<LayerAlignment> = (0..shared)
+        .map(|i| LayerAlignment::Shared { old: i, new: i })
+        .collect();
+
+    // Longest common subsequence of the commands of the rest
+    let (old_rest, new_rest) = (&old[shared..], &new[shared..]);
+    let mut lengths = vec![vec![0usize; new_rest.len() + 1]; old_rest.len() + 1];
+    for i in (0..old_rest.len()).rev() {
+        for j in (0..new_rest.len()).rev() {
+            lengths[i][j] = if old_rest[i].command == new_rest[j].command {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    while i < old_rest.len() || j < new_rest.len() {
+        if i < old_rest.len() && j < new_rest.len() && old_rest[i].command == new_rest[j].command {
+            alignment.push(LayerAlignment::Rebuilt {
+                old: shared + i,
+                new: shared + j,
+                same_content: old_rest[i].name == new_rest[j].name,
+            });
+            i += 1;
+            j += 1;
+        } else if j < new_rest.len() && (i == old_rest.len() || lengths[i][j + 1] >= lengths[i + 1][j]) {
+            alignment.push(LayerAlignment::New(shared + j));
+            j += 1;
+        } else {
+            alignment.push(LayerAlignment::Dropped(shared + i));
+            i += 1;
+        }
+    }
+    alignment
+}
+
+/// Writes the alignment of the layers and the changes of the final filesystem
+pub fn write_report(
+    out: &mut impl Write,
+    (old_name, old): (&str, &ImageRepr),
+    (new_name, new): (&str, &ImageRepr),
+) -> io::Result<()> {
+    writeln!(out, "Layers of {} -> {}", old_name, new_name)?;
+    write_alignment(out, &old.layers, &new.layers)?;
+
+    let diff = old.final_tree().diff(&new.final_tree());
+    write_tree_diff(out, &diff)
+}
+
+/// Writes one line per aligned layer. Layers that failed to load are marked, their changes
+/// are missing from the filesystem diff.
+pub fn write_alignment(out: &mut impl Write, old: &[ImageLayer], new: &[ImageLayer]) -> io::Result<()> {
+    for aligned in align_layers(old, new) {
+        let (label, old_index, new_index, layer) = match aligned {
+            LayerAlignment::Shared { old: o, new: n } => ("shared", Some(o), Some(n), &new[n]),
+            LayerAlignment::Rebuilt { old: o, new: n, same_content: true } => ("same", Some(o), Some(n), &new[n]),
+            LayerAlignment::Rebuilt { old: o, new: n, .. } => ("rebuilt", Some(o), Some(n), &new[n]),
+            LayerAlignment::New(n) => ("new", None, Some(n), &new[n]),
+            LayerAlignment::Dropped(o) => ("dropped", Some(o), None, &old[o]),
+        };
+        let failed = old_index.is_some_and(|o| old[o].error.is_some())
+            || new_index.is_some_and(|n| new[n].error.is_some());
+        let index = |index: Option<usize>| index.map(ImageLayer::label).unwrap_or_default();
+        writeln!(
+            out,
+            "  {:<8} {:>4} {:>4}  {}{}",
+            label,
+            index(old_index),
+            index(new_index),
+            layer.short_command(),
+            if failed { " (failed to load)" } else { "" }
+        )?;
+    }
+    Ok(())
+}
+
+/// Writes the changed entries, one per line. Added and removed directories are listed
+/// without their contents and count as one entry.
+pub fn write_tree_diff(out: &mut impl Write, diff: &TreeDiff) -> io::Result<()> {
+    let mut listed: Vec<(PathChange, i64, String)> = Vec::new();
+    diff.tree.walk(|node| {
+        let path = node.path();
+        // Directories that are only there because of the changes below them are not listed
+        let Some((change, size_delta)) = diff
+            .entry(&path)
+            .and_then(|entry| Some((entry.change?, entry.size_delta)))
+        else {
+            return WalkControl::Continue;
+        };
+        let whole_directory = node.ftype() == EntryType::Directory
+            && matches!(change, PathChange::Added | PathChange::Removed);
+        let suffix = if whole_directory { "/" } else { "" };
+        listed.push((change, size_delta, format!("{}{}", escape_name(path.as_os_str()), suffix)));
+        if whole_directory {
+            WalkControl::SkipChildren
+        } else {
+            WalkControl::Continue
+        }
+    });
+
+    let count = |change| listed.iter().filter(|(listed, _, _)| *listed == change).count();
+    writeln!(
+        out,
+        "Filesystem: {} added, {} modified, {} metadata only, {} removed, {} in total",
+        count(PathChange::Added),
+        count(PathChange::Modified),
+        count(PathChange::Metadata),
+        count(PathChange::Removed),
+        format_size_delta(diff.size_delta())
+    )?;
+    for (change, size_delta, path) in listed.iter() {
+        writeln!(out, "  {:<8} {:>10}  {}", change.to_string(), format_size_delta(*size_delta), path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_tree::FileTree;
+    #[cfg(unix)]
+    use crate::file_tree::test_layer;
+
+    fn layers(layers: &[(&str, &str)]) -> Vec<ImageLayer> {
+        layers
+            .iter()
+            .map(|(diff_id, command)| {
+                ImageLayer::new(diff_id.to_string(), FileTree::empty(), command.to_string(), None)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn layer_alignment() {
+        let old = layers(&[
+            ("sha256:base", "ADD rootfs.tar.xz /"),
+            ("sha256:apt1", "RUN apt-get install -y curl"),
+            ("sha256:app1", "COPY . /app"),
+            ("sha256:cfg", "COPY config.yaml /etc/app/"),
+            ("sha256:dbg", "RUN rm -rf /tmp/debug"),
+        ]);
+        let new = layers(&[
+            ("sha256:base", "ADD rootfs.tar.xz /"),
+            ("sha256:apt2", "RUN apt-get install -y curl"),
+            ("sha256:app2", "COPY . /app"),
+            ("sha256:usr", "USER app"),
+            ("sha256:cfg", "COPY config.yaml /etc/app/"),
+        ]);
+        assert_eq!(
+            align_layers(&old, &new),
+            [
+                LayerAlignment::Shared { old: 0, new: 0 },
+                LayerAlignment::Rebuilt { old: 1, new: 1, same_content: false },
+                LayerAlignment::Rebuilt { old: 2, new: 2, same_content: false },
+                LayerAlignment::New(3),
+                LayerAlignment::Rebuilt { old: 3, new: 4, same_content: true },
+                LayerAlignment::Dropped(4),
+            ]
+        );
+        assert_eq!(align_layers(&old, &old).len(), old.len());
+    }
+
+    #[test]
+    fn failed_layers_are_marked() {
+        let old = layers(&[("sha256:base", "ADD rootfs.tar.xz /"), ("sha256:app1", "COPY . /app")]);
+        let mut new = layers(&[("sha256:base", "ADD rootfs.tar.xz /"), ("sha256:app2", "COPY . /app")]);
+        new[1].error = Some("Can't read the layer".to_string());
+
+        let mut out = Vec::new();
+        write_alignment(&mut out, &old, &new).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "  shared     #0   #0  ADD rootfs.tar.xz /\n\
+             \x20 rebuilt    #1   #1  COPY . /app (failed to load)\n"
+        );
+        assert_eq!(ImageLayer::failed_labels(&new), ["#1"]);
+        assert!(ImageLayer::failed_labels(&old).is_empty());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn tree_diff_report() {
+        let old = test_layer(&[("etc/hosts", "content"), ("tmp/cache/a", "content"), ("tmp/cache/b", "content")]);
+        let new = test_layer(&[
+            ("etc/hosts", "content"),
+            ("app/bin/app", "content"),
+            ("app/lib/lib.so", "content"),
+            ("tmp/x", "content"),
+        ]);
+        let mut out = Vec::new();
+        write_tree_diff(&mut out, &old.diff(&new)).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Filesystem: 2 added, 0 modified, 0 metadata only, 1 removed, +7 B in total\n\
+             \x20 Add           +14 B  /app/\n\
+             \x20 Remove        -14 B  /tmp/cache/\n\
+             \x20 Add            +7 B  /tmp/x\n"
+        );
+    }
+}
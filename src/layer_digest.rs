@@ -0,0 +1,116 @@
+use crate::exceptions::{ImageParcingError, PathContext};
+use flate2::read::GzDecoder;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Reader that computes the sha256 of everything that is read through it
+pub struct HashingReader<R: Read> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> Self {
+        HashingReader {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Reads the rest of the stream, e.g. the padding after the end of a tar archive,
+    /// and returns the digest in the `sha256:<hex>` form used by docker for diff_ids
+    pub fn finalize(mut self) -> std::io::Result<String> {
+        std::io::copy(&mut self, &mut std::io::sink())?;
+        Ok(format!("sha256:{:x}", self.hasher.finalize()))
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Opens a layer blob and returns a reader over the uncompressed tar stream.
+/// Docker exports layers either as plain tars or gzipped ones, depending on the image store.
+pub fn open_layer_blob(blob_path: &Path) -> Result<Box<dyn Read>, ImageParcingError> {
+    let file = File::open(blob_path).with_path(blob_path)?;
+    let mut reader = BufReader::new(file);
+    let is_gzip = reader.fill_buf().with_path(blob_path)?.starts_with(&GZIP_MAGIC);
+    if is_gzip {
+        Ok(Box::new(GzDecoder::new(reader)))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    fn tar_bytes() -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let content = b"hello";
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "hello.txt", &content[..])
+            .unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    fn compute_diff_id(blob_path: &Path) -> String {
+        HashingReader::new(open_layer_blob(blob_path).unwrap())
+            .finalize()
+            .unwrap()
+    }
+
+    fn expected_diff_id(bytes: &[u8]) -> String {
+        format!("sha256:{:x}", Sha256::digest(bytes))
+    }
+
+    #[test]
+    fn diff_id_of_plain_tar() {
+        let dir = TempDir::new().unwrap();
+        let tar = tar_bytes();
+        let blob_path = dir.path().join("blob");
+        File::create(&blob_path).unwrap().write_all(&tar).unwrap();
+        assert_eq!(compute_diff_id(&blob_path), expected_diff_id(&tar));
+    }
+
+    #[test]
+    fn diff_id_of_gzipped_tar() {
+        let dir = TempDir::new().unwrap();
+        let tar = tar_bytes();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&tar).unwrap();
+        let blob_path = dir.path().join("blob");
+        File::create(&blob_path)
+            .unwrap()
+            .write_all(&encoder.finish().unwrap())
+            .unwrap();
+        assert_eq!(compute_diff_id(&blob_path), expected_diff_id(&tar));
+    }
+
+    #[test]
+    fn hash_includes_data_after_archive_end() {
+        let tar = tar_bytes();
+        let mut archive = tar::Archive::new(HashingReader::new(&tar[..]));
+        let dir = TempDir::new().unwrap();
+        archive.unpack(dir.path()).unwrap();
+        let digest = archive.into_inner().finalize().unwrap();
+        assert_eq!(digest, expected_diff_id(&tar));
+    }
+}
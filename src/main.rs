@@ -1,69 +1,244 @@
+mod capabilities;
+mod ci;
+mod cleanup;
 mod docker_image_utils;
+mod efficiency;
 mod exceptions;
+mod file_names;
 mod file_tree;
 mod gui_app;
+mod history;
+mod image_diff;
+mod layer_digest;
+mod query;
+mod search;
 mod widgets;
 use bollard::Docker;
-use clap::{Arg, Command};
-use docker_image_utils::ImageRepr;
+use clap::{Arg, ArgAction, Command};
+use docker_image_utils::{CacheEntryStatus, ImageLayer, ImageRepr};
+use exceptions::{ImageParcingError, PathContext};
 use gui_app::App;
 use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 use std::time::Instant;
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    let matches = Command::new("FreightView")
-        .version(env!("CARGO_PKG_VERSION"))
-        .author("Georgii Krikun <georgii.krikun@gmail.com>")
-        .about("Browse contents of docker image in the intractive terminal; press 'h' inside the app to list controls")
-        .arg(Arg::new("Name").help("Name of the image").required(true))
-        .get_matches();
-
-    let img_name: String = matches
-        .get_one::<String>("Name")
-        .expect("Can't parse to string")
-        .clone();
-
-    // Massage name to append latest tag if not specified
-    let img_name = if img_name.contains(':') {
-        img_name
+/// Massage name to append latest tag if not specified
+fn normalize_image_name(img_name: &str) -> String {
+    if img_name.contains(':') {
+        img_name.to_string()
     } else {
         format!("{}:latest", img_name)
-    };
+    }
+}
 
-    let docker = Docker::connect_with_socket_defaults().expect("Can't connect to docker");
+fn connect_to_docker() -> Result<Docker, ImageParcingError> {
+    Docker::connect_with_socket_defaults()
+        .map_err(|source| ImageParcingError::CantConnectToDocker { source })
+}
 
-    let start = Instant::now();
-    let img = ImageRepr::new(img_name.clone(), &docker).await;
-    let cleanup_result = ImageRepr::clean_up_img_cache(&img_name);
-    match cleanup_result {
-        Ok(_) => {}
-        Err(e) => {
-            eprintln!(
-                "Error cleaning up image cache: {}, please cleanup manually, otherwise large cache will stay on your hard drive",
-                e
-            );
-            return Err(Box::from(e));
+/// Prints the error together with the chain of errors that caused it
+fn report_error(err: &dyn Error) {
+    eprintln!("Error: {}", err);
+    let mut source = err.source();
+    while let Some(cause) = source {
+        eprintln!("  caused by: {}", cause);
+        source = cause.source();
+    }
+}
+
+async fn verify_cache(img_name: &str, docker: &Docker) -> Result<(), ImageParcingError> {
+    let reports = ImageRepr::verify_cache(img_name, docker).await;
+    ImageRepr::clean_up_img_cache(img_name)?;
+    let reports = reports?;
+
+    let mut n_invalid = 0;
+    for report in reports.iter() {
+        println!("{}: {}", report.diff_id, report.status);
+        match report.status {
+            CacheEntryStatus::Valid | CacheEntryStatus::NotCached => {}
+            _ => n_invalid += 1,
         }
     }
 
+    if n_invalid > 0 {
+        return Err(ImageParcingError::CacheVerificationFailed {
+            image: img_name.to_string(),
+            n_invalid,
+        });
+    }
+    println!("All cached layers of {} are valid", img_name);
+    Ok(())
+}
+
+/// Loads the image and removes the export it was read from
+async fn load_image(img_name: &str, docker: &Docker) -> Result<ImageRepr, ImageParcingError> {
+    let img = ImageRepr::new(img_name.to_string(), docker).await;
+    let cleanup_result = ImageRepr::clean_up_img_cache(img_name);
+    if let Err(e) = cleanup_result {
+        eprintln!(
+            "Error cleaning up image cache: {}, please cleanup manually, otherwise large cache will stay on your hard drive",
+            e
+        );
+        return Err(e);
+    }
+    img
+}
+
+async fn browse_image(img_name: &str, docker: &Docker) -> Result<(), ImageParcingError> {
+    let start = Instant::now();
+    let img = load_image(img_name, docker).await?;
+
     let elapsed = start.elapsed();
     println!("Startup time: {:?}", elapsed);
 
-    let img = match img {
-        Ok(img) => img,
-        Err(e) => {
-            eprintln!("Error: {}", e);
-            return Err(Box::from(e));
-        }
-    };
-
-    let mut terminal = ratatui::init();
+    let mut terminal = cleanup::init_terminal();
 
     let mut app = App::new(img);
     let _ = app.run(&mut terminal);
 
-    ratatui::restore();
+    cleanup::restore_terminal();
 
     Ok(())
 }
+
+/// Prints why layers failed to load; the interface shows this below the layer command instead
+fn report_failed_layers(img_name: &str, layers: &[ImageLayer]) {
+    for (index, layer) in layers.iter().enumerate() {
+        if let Some(error) = &layer.error {
+            eprintln!("Layer {} of {} failed to load: {}", ImageLayer::label(index), img_name, error);
+        }
+    }
+}
+
+async fn diff_images(old_name: &str, new_name: &str, docker: &Docker) -> Result<(), ImageParcingError> {
+    let old = load_image(old_name, docker).await?;
+    let new = load_image(new_name, docker).await?;
+    for (name, image) in [(old_name, &old), (new_name, &new)] {
+        let failed = ImageLayer::failed_labels(&image.layers);
+        if !failed.is_empty() {
+            report_failed_layers(name, &image.layers);
+            eprintln!(
+                "Warning: layers {} of {} failed to load, the filesystem diff misses their changes",
+                failed.join(", "),
+                name
+            );
+        }
+    }
+    let mut out = std::io::stdout().lock();
+    image_diff::write_report(&mut out, (old_name, &old), (new_name, &new))
+        .with_path(Path::new("<stdout>"))?;
+    Ok(())
+}
+
+/// Checks the image against the CI rules without starting the interface
+async fn check_image(img_name: &str, config: Option<&Path>, docker: &Docker) -> Result<(), ImageParcingError> {
+    // A broken config should fail before the image is exported
+    let config = ci::CiConfig::load(config)?;
+    let img = load_image(img_name, docker).await?;
+    report_failed_layers(img_name, &img.layers);
+    let trees: Vec<&file_tree::FileTree> = img.layers.iter().map(|layer| &layer.tree).collect();
+    let efficiency = efficiency::Efficiency::analyze(&trees);
+    let results = ci::evaluate(&config.rules, &efficiency, &ImageLayer::failed_labels(&img.layers));
+
+    let mut out = std::io::stdout().lock();
+    ci::write_report(&mut out, img_name, &efficiency, &results).with_path(Path::new("<stdout>"))?;
+    let n_failed = results.iter().filter(|result| !result.passed).count();
+    if n_failed > 0 {
+        return Err(ImageParcingError::CiRulesFailed {
+            image: img_name.to_string(),
+            n_failed,
+        });
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    cleanup::install_panic_hook();
+    cleanup::spawn_signal_handler();
+
+    let matches = Command::new("FreightView")
+        .version(env!("CARGO_PKG_VERSION"))
+        .author("Georgii Krikun <georgii.krikun@gmail.com>")
+        .about("Browse contents of docker image in the intractive terminal; press 'h' inside the app to list controls")
+        .args_conflicts_with_subcommands(true)
+        .arg(Arg::new("Name").help("Name of the image").required(true))
+        .arg(
+            Arg::new("ci")
+                .long("ci")
+                .action(ArgAction::SetTrue)
+                .help("Check the efficiency of the image against the rules of .freightview.yaml instead of browsing it; exits with 8 if a rule fails"),
+        )
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .value_name("FILE")
+                .value_parser(clap::value_parser!(PathBuf))
+                .requires("ci")
+                .help("CI rules to use instead of ./.freightview.yaml"),
+        )
+        .subcommand(
+            Command::new("diff")
+                .about("Compare two images: which layers they share and what changed in the final filesystem")
+                .arg(Arg::new("Old").help("Name of the image to compare against").required(true))
+                .arg(Arg::new("New").help("Name of the newer image").required(true)),
+        )
+        .subcommand(
+            Command::new("cache")
+                .about("Manage the layer cache")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("verify")
+                        .about("Re-check cached layers of a local image against their diff_ids")
+                        .arg(Arg::new("Name").help("Name of the image").required(true)),
+                ),
+        )
+        .get_matches();
+
+    let result = match matches.subcommand() {
+        Some(("diff", diff_matches)) => {
+            let name = |arg: &str| {
+                normalize_image_name(diff_matches.get_one::<String>(arg).expect("Can't parse to string"))
+            };
+            match connect_to_docker() {
+                Ok(docker) => diff_images(&name("Old"), &name("New"), &docker).await,
+                Err(e) => Err(e),
+            }
+        }
+        Some(("cache", cache_matches)) => match cache_matches.subcommand() {
+            Some(("verify", verify_matches)) => {
+                let img_name = verify_matches
+                    .get_one::<String>("Name")
+                    .expect("Can't parse to string");
+                match connect_to_docker() {
+                    Ok(docker) => verify_cache(&normalize_image_name(img_name), &docker).await,
+                    Err(e) => Err(e),
+                }
+            }
+            _ => unreachable!("clap requires a cache subcommand"),
+        },
+        _ => {
+            let img_name = matches
+                .get_one::<String>("Name")
+                .expect("Can't parse to string");
+            let img_name = normalize_image_name(img_name);
+            let config = matches.get_one::<PathBuf>("config");
+            match connect_to_docker() {
+                Ok(docker) if matches.get_flag("ci") => {
+                    check_image(&img_name, config.map(PathBuf::as_path), &docker).await
+                }
+                Ok(docker) => browse_image(&img_name, &docker).await,
+                Err(e) => Err(e),
+            }
+        }
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            report_error(&e);
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
@@ -0,0 +1,377 @@
+use crate::file_names::escape_name;
+use crate::file_tree::{EntryOp, EntryType, FileTreeNodeData};
+use crate::search::{PatternError, glob_to_regex, short_error};
+use regex::Regex;
+
+/// Fields a query can filter on, for error messages
+const FIELDS: &str = "size, type, op, path, name, perm, owner, group";
+
+/// A filter such as `size>10M type:file op:remove path:/usr/**` or `perm:+s owner:root`.
+/// Terms are separated by spaces and every one of them has to match; `!` in front of a term
+/// negates it and a word without a field matches names that contain it. Values with spaces
+/// can be put in double quotes.
+#[derive(Debug)]
+pub struct Query {
+    // Terms along with whether they are negated
+    terms: Vec<(bool, Term)>,
+}
+
+#[derive(Debug)]
+enum Term {
+    Size(Comparison, u64),
+    Type(TypeFilter),
+    Op(EntryOp),
+    // Globs, compiled
+    Path(Regex),
+    Name(Regex),
+    Mode(ModeFilter),
+    Owner(u64),
+    Group(u64),
+    Text(String),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Comparison {
+    Less,
+    LessOrEqual,
+    Equal,
+    GreaterOrEqual,
+    Greater,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum TypeFilter {
+    Directory,
+    File,
+    Symlink,
+    Hardlink,
+    CharDevice,
+    BlockDevice,
+    Fifo,
+    Socket,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ModeFilter {
+    // The permission bits are exactly these
+    Exactly(u32),
+    // All of these bits are set
+    AllOf(u32),
+    // At least one of these bits is set
+    AnyOf(u32),
+}
+
+/// Why a query could not be parsed; `column` is where the offending term starts, from 1
+#[derive(Debug, Eq, PartialEq, thiserror::Error)]
+#[error("{message} (column {column})")]
+pub struct QueryError {
+    pub column: usize,
+    pub message: String,
+}
+
+impl Query {
+    pub fn parse(query: &str) -> Result<Query, QueryError> {
+        let terms = tokenize(query)?
+            .into_iter()
+            .map(|(column, token)| {
+                let (negated, token) = match token.strip_prefix('!') {
+                    Some(token) => (true, token.to_string()),
+                    None => (false, token),
+                };
+                parse_term(&token).map(|term| (negated, term)).map_err(|message| QueryError {
+                    column,
+                    message,
+                })
+            })
+            .collect::<Result<Vec<(bool, Term)>, QueryError>>()?;
+        Ok(Query { terms })
+    }
+
+    pub fn matches(&self, data: &FileTreeNodeData) -> bool {
+        self.terms
+            .iter()
+            .all(|(negated, term)| term.matches(data) != *negated)
+    }
+}
+
+impl Term {
+    fn matches(&self, data: &FileTreeNodeData) -> bool {
+        match self {
+            Term::Size(comparison, size) => match comparison {
+                Comparison::Less => data.size() < *size,
+                Comparison::LessOrEqual => data.size() <= *size,
+                Comparison::Equal => data.size() == *size,
+                Comparison::GreaterOrEqual => data.size() >= *size,
+                Comparison::Greater => data.size() > *size,
+            },
+            Term::Type(filter) => TypeFilter::of(&data.ftype()) == Some(*filter),
+            Term::Op(op) => data.fop() == *op,
+            Term::Path(regex) => regex.is_match(&escape_name(data.path().as_os_str())),
+            Term::Name(regex) => regex.is_match(&data.display_name()),
+            Term::Mode(ModeFilter::Exactly(bits)) => data.mode() == *bits,
+            Term::Mode(ModeFilter::AllOf(bits)) => data.mode() & bits == *bits,
+            Term::Mode(ModeFilter::AnyOf(bits)) => data.mode() & bits != 0,
+            Term::Owner(uid) => data.uid() == *uid,
+            Term::Group(gid) => data.gid() == *gid,
+            Term::Text(text) => data.display_name().contains(text.as_str()),
+        }
+    }
+}
+
+impl TypeFilter {
+    fn of(ftype: &EntryType) -> Option<TypeFilter> {
+        match ftype {
+            EntryType::Directory => Some(TypeFilter::Directory),
+            EntryType::File => Some(TypeFilter::File),
+            EntryType::Symlink(_) => Some(TypeFilter::Symlink),
+            EntryType::Hardlink(_) => Some(TypeFilter::Hardlink),
+            EntryType::CharDevice { .. } => Some(TypeFilter::CharDevice),
+            EntryType::BlockDevice { .. } => Some(TypeFilter::BlockDevice),
+            EntryType::Fifo => Some(TypeFilter::Fifo),
+            EntryType::Socket => Some(TypeFilter::Socket),
+            EntryType::Badfile => None,
+        }
+    }
+}
+
+/// Splits the query into terms along with the column each one starts at
+fn tokenize(query: &str) -> Result<Vec<(usize, String)>, QueryError> {
+    let mut tokens: Vec<(usize, String)> = Vec::new();
+    let mut current: Option<(usize, String)> = None;
+    let mut quote_column: Option<usize> = None;
+
+    for (column, c) in query.chars().enumerate().map(|(i, c)| (i + 1, c)) {
+        match c {
+            '"' => {
+                quote_column = match quote_column {
+                    Some(_) => None,
+                    None => Some(column),
+                };
+                current.get_or_insert_with(|| (column, String::new()));
+            }
+            c if c.is_whitespace() && quote_column.is_none() => {
+                tokens.extend(current.take());
+            }
+            c => current.get_or_insert_with(|| (column, String::new())).1.push(c),
+        }
+    }
+    if let Some(column) = quote_column {
+        return Err(QueryError {
+            column,
+            message: "unclosed quote".to_string(),
+        });
+    }
+    tokens.extend(current);
+    Ok(tokens)
+}
+
+fn parse_term(token: &str) -> Result<Term, String> {
+    if let Some((comparison, value)) = token.strip_prefix("size").and_then(parse_comparison) {
+        if value.is_empty() {
+            return Err("missing value for 'size'".to_string());
+        }
+        return Ok(Term::Size(comparison, parse_size(value)?));
+    }
+
+    let Some((field, value)) = token.split_once(':') else {
+        return Ok(Term::Text(token.to_string()));
+    };
+    if value.is_empty() {
+        return Err(format!("missing value for '{}'", field));
+    }
+    match field {
+        "type" => parse_type(value).map(Term::Type),
+        "op" => match value {
+            "add" | "added" => Ok(Term::Op(EntryOp::Add)),
+            "modify" | "modified" => Ok(Term::Op(EntryOp::Modify)),
+            "remove" | "removed" => Ok(Term::Op(EntryOp::Remove)),
+            _ => Err(format!("unknown op '{}', expected add, modify or remove", value)),
+        },
+        "path" => parse_glob(value, true).map(Term::Path),
+        "name" => parse_glob(value, false).map(Term::Name),
+        "perm" => parse_mode(value).map(Term::Mode),
+        "owner" | "uid" => parse_id(value, "user").map(Term::Owner),
+        "group" | "gid" => parse_id(value, "group").map(Term::Group),
+        _ => Err(format!("unknown field '{}', expected one of {}", field, FIELDS)),
+    }
+}
+
+fn parse_comparison(rest: &str) -> Option<(Comparison, &str)> {
+    // Two character operators first, so that `>=` is not taken for `>`
+    let operators = [
+        ("<=", Comparison::LessOrEqual),
+        (">=", Comparison::GreaterOrEqual),
+        ("<", Comparison::Less),
+        (">", Comparison::Greater),
+        ("=", Comparison::Equal),
+        (":", Comparison::Equal),
+    ];
+    operators
+        .into_iter()
+        .find_map(|(operator, comparison)| rest.strip_prefix(operator).map(|value| (comparison, value)))
+}
+
+/// Parses sizes like `512`, `10M` or `1.5GB`; units are powers of 1024, as in the tree
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let multiplier: u64 = match unit.to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1 << 10,
+        "m" | "mb" => 1 << 20,
+        "g" | "gb" => 1 << 30,
+        "t" | "tb" => 1 << 40,
+        _ => return Err(format!("unknown size unit '{}', expected B, K, M, G or T", unit)),
+    };
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid size '{}'", value))?;
+    Ok((number * multiplier as f64) as u64)
+}
+
+fn parse_type(value: &str) -> Result<TypeFilter, String> {
+    match value {
+        "dir" | "directory" | "d" => Ok(TypeFilter::Directory),
+        "file" | "f" => Ok(TypeFilter::File),
+        "symlink" | "link" | "l" => Ok(TypeFilter::Symlink),
+        "hardlink" => Ok(TypeFilter::Hardlink),
+        "char" | "c" => Ok(TypeFilter::CharDevice),
+        "block" | "b" => Ok(TypeFilter::BlockDevice),
+        "fifo" | "p" => Ok(TypeFilter::Fifo),
+        "socket" | "s" => Ok(TypeFilter::Socket),
+        _ => Err(format!(
+            "unknown type '{}', expected file, dir, symlink, hardlink, char, block, fifo or socket",
+            value
+        )),
+    }
+}
+
+fn parse_glob(value: &str, full_path: bool) -> Result<Regex, String> {
+    glob_to_regex(value, full_path)
+        .and_then(|regex| Ok(Regex::new(&regex)?))
+        .map_err(|e: PatternError| format!("invalid pattern '{}': {}", value, short_error(&e)))
+}
+
+/// `perm:+s` is setuid or setgid, `perm:+t` the sticky bit, `perm:+2` all of the given octal
+/// bits and `perm:755` exactly that mode
+fn parse_mode(value: &str) -> Result<ModeFilter, String> {
+    let octal = |digits: &str| {
+        u32::from_str_radix(digits, 8)
+            .ok()
+            .filter(|bits| *bits <= 0o7777)
+            .ok_or_else(|| format!("invalid permissions '{}', expected an octal mode, +s or +t", value))
+    };
+    match value.strip_prefix('+') {
+        Some("s") => Ok(ModeFilter::AnyOf(0o6000)),
+        Some("t") => Ok(ModeFilter::AllOf(0o1000)),
+        Some(bits) => octal(bits).map(ModeFilter::AllOf),
+        None => octal(value).map(ModeFilter::Exactly),
+    }
+}
+
+/// Users and groups are numeric, the image's /etc/passwd is not read; only `root` is known
+fn parse_id(value: &str, kind: &str) -> Result<u64, String> {
+    if value == "root" {
+        return Ok(0);
+    }
+    value
+        .parse()
+        .map_err(|_| format!("unknown {} '{}', use a numeric id", kind, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_tree::FileTree;
+    use std::io::Read;
+    use std::path::Path;
+
+    // Path, type, mode, uid and size of the entries the queries run on
+    type Entry<'a> = (&'a str, tar::EntryType, u32, u64, u64);
+
+    fn matching(query: &str, entries: &[Entry]) -> Vec<String> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, entry_type, mode, uid, size) in entries {
+            let path = path.trim_start_matches('/');
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(*entry_type);
+            header.set_mode(*mode);
+            header.set_uid(*uid);
+            if *entry_type == tar::EntryType::Symlink {
+                header.set_size(0);
+                builder.append_link(&mut header, path, "target").unwrap();
+            } else {
+                header.set_size(*size);
+                builder
+                    .append_data(&mut header, path, std::io::repeat(0).take(*size))
+                    .unwrap();
+            }
+        }
+        let bytes = builder.into_inner().unwrap();
+        let tree = FileTree::from_archive(&mut tar::Archive::new(&bytes[..]), Path::new("layer.tar"))
+            .unwrap();
+
+        let query = Query::parse(query).unwrap();
+        entries
+            .iter()
+            .filter(|(path, ..)| query.matches(&tree.get(path).unwrap()))
+            .map(|(path, ..)| path.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn queries() {
+        let entries: [Entry; 5] = [
+            ("/usr/bin/passwd", tar::EntryType::Regular, 0o4755, 0, 600),
+            ("/usr/lib/libLLVM.so", tar::EntryType::Regular, 0o644, 0, 100 << 10),
+            ("/usr/lib/libc.so", tar::EntryType::Symlink, 0o777, 0, 0),
+            ("/tmp", tar::EntryType::Directory, 0o1777, 0, 0),
+            ("/home/app/big.log", tar::EntryType::Regular, 0o644, 1000, 20 << 10),
+        ];
+        assert_eq!(
+            matching("size>10K type:file", &entries),
+            ["/usr/lib/libLLVM.so", "/home/app/big.log"]
+        );
+        assert_eq!(
+            matching("size>10k type:file owner:root", &entries),
+            ["/usr/lib/libLLVM.so"]
+        );
+        assert_eq!(matching("perm:+s", &entries), ["/usr/bin/passwd"]);
+        assert_eq!(matching("perm:+t", &entries), ["/tmp"]);
+        assert_eq!(matching("perm:644 !owner:root", &entries), ["/home/app/big.log"]);
+        assert_eq!(
+            matching("path:/usr/** !type:symlink", &entries),
+            ["/usr/bin/passwd", "/usr/lib/libLLVM.so"]
+        );
+        assert_eq!(matching("name:*.so", &entries), ["/usr/lib/libLLVM.so", "/usr/lib/libc.so"]);
+        assert_eq!(matching("LLVM size<=100KB", &entries), ["/usr/lib/libLLVM.so"]);
+        // Words that only start like a field are names
+        assert_eq!(matching("big", &entries), ["/home/app/big.log"]);
+        assert_eq!(matching(r#"path:"/home/app/*""#, &entries), ["/home/app/big.log"]);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let error = |query: &str| Query::parse(query).unwrap_err();
+        assert_eq!(
+            error("type:file colour:red"),
+            QueryError {
+                column: 11,
+                message: format!("unknown field 'colour', expected one of {}", FIELDS)
+            }
+        );
+        assert_eq!(error("size>10Q").message, "unknown size unit 'Q', expected B, K, M, G or T");
+        assert_eq!(error("type:file size>").column, 11);
+        assert_eq!(error("op:rename").message, "unknown op 'rename', expected add, modify or remove");
+        assert_eq!(error("perm:+9").column, 1);
+        assert_eq!(error("owner:bob").message, "unknown user 'bob', use a numeric id");
+        assert_eq!(error(r#"path:"/usr"#).message, "unclosed quote");
+        assert_eq!(error("type:").message, "missing value for 'type'");
+        assert_eq!(
+            error("size>1 type:pipe").to_string(),
+            "unknown type 'pipe', expected file, dir, symlink, hardlink, char, block, fifo or socket (column 8)"
+        );
+    }
+}
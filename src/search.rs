@@ -0,0 +1,334 @@
+use crate::exceptions::GUIError;
+use crate::file_names::escape_name;
+use crate::file_tree::{EntryOp, FileTree, NodeRef};
+use crate::query::{Query, QueryError};
+use regex::Regex;
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+
+/// How the search string is matched against the entries of the tree
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum SearchMode {
+    /// Path components from the root, the last one may be incomplete
+    Path,
+    /// Names that contain the search string
+    Substring,
+    /// Shell patterns, `**` spans directories; patterns with a `/` match the full path,
+    /// others the name
+    Glob,
+    /// Regular expression searched for in the full path
+    Regex,
+    /// Names that contain the characters of the search string in order, ignoring case
+    Fuzzy,
+    /// Filters on the metadata of the entries, see `Query`
+    Query,
+}
+
+impl SearchMode {
+    pub fn next(self) -> SearchMode {
+        match self {
+            SearchMode::Path => SearchMode::Substring,
+            SearchMode::Substring => SearchMode::Glob,
+            SearchMode::Glob => SearchMode::Regex,
+            SearchMode::Regex => SearchMode::Fuzzy,
+            SearchMode::Fuzzy => SearchMode::Query,
+            SearchMode::Query => SearchMode::Path,
+        }
+    }
+
+    pub fn title(self) -> &'static str {
+        match self {
+            SearchMode::Path => "path",
+            SearchMode::Substring => "substring",
+            SearchMode::Glob => "glob",
+            SearchMode::Regex => "regex",
+            SearchMode::Fuzzy => "fuzzy",
+            SearchMode::Query => "query",
+        }
+    }
+}
+
+/// A search string compiled for one of the modes that search the whole tree
+pub enum Matcher {
+    Substring(String),
+    Pattern { regex: Regex, full_path: bool },
+    Fuzzy(Vec<char>),
+    Query(Query),
+}
+
+/// Why a search string can't be used
+#[derive(Debug, thiserror::Error)]
+pub enum PatternError {
+    #[error(transparent)]
+    Regex(#[from] regex::Error),
+    #[error(transparent)]
+    Query(#[from] QueryError),
+    #[error("'[' is never closed")]
+    UnclosedClass,
+}
+
+/// The error on a single line, to fit under a search bar. Errors of a regex span several
+/// lines and point at the pattern, the last one says what is wrong.
+pub fn short_error(error: &PatternError) -> String {
+    error.to_string().lines().last().unwrap_or_default().trim().to_string()
+}
+
+impl Matcher {
+    /// Compiles the search string; fails on invalid regular expressions and queries. The path
+    /// mode walks down the tree instead of matching entries, here it is taken as a substring.
+    pub fn new(mode: SearchMode, pattern: &str) -> Result<Matcher, PatternError> {
+        match mode {
+            SearchMode::Path | SearchMode::Substring => Ok(Matcher::Substring(pattern.to_string())),
+            SearchMode::Glob => Ok(Matcher::Pattern {
+                regex: Regex::new(&glob_to_regex(pattern, pattern.contains('/'))?)?,
+                full_path: pattern.contains('/'),
+            }),
+            SearchMode::Regex => Ok(Matcher::Pattern {
+                regex: Regex::new(pattern)?,
+                full_path: true,
+            }),
+            SearchMode::Fuzzy => Ok(Matcher::Fuzzy(pattern.to_lowercase().chars().collect())),
+            SearchMode::Query => Ok(Matcher::Query(Query::parse(pattern)?)),
+        }
+    }
+
+    /// Names and paths are matched in the escaped form they are shown in
+    pub fn matches(&self, node: NodeRef) -> bool {
+        match self {
+            Matcher::Substring(pattern) => node.display_name().contains(pattern.as_str()),
+            Matcher::Pattern { regex, full_path: true } => {
+                regex.is_match(&escape_name(node.path().as_os_str()))
+            }
+            Matcher::Pattern { regex, full_path: false } => regex.is_match(&node.display_name()),
+            Matcher::Fuzzy(pattern) => fuzzy_match(pattern, &node.display_name()),
+            Matcher::Query(query) => query.matches(&node),
+        }
+    }
+}
+
+/// A tree narrowed down to what the search string matches
+pub struct SearchResult<'a> {
+    pub tree: Cow<'a, FileTree>,
+    // Paths of the matching entries, the directories above them are to be expanded
+    pub matches: Vec<PathBuf>,
+    pub error: Option<GUIError>,
+}
+
+/// Filters the tree with the search string. On errors and when nothing matches the whole
+/// tree is returned along with the error.
+pub fn search_tree<'a>(tree: &'a FileTree, mode: SearchMode, pattern: &str) -> SearchResult<'a> {
+    let unfiltered = |error: Option<GUIError>| SearchResult {
+        tree: Cow::Borrowed(tree),
+        matches: Vec::new(),
+        error,
+    };
+    if pattern.is_empty() {
+        return unfiltered(None);
+    }
+    if mode == SearchMode::Path {
+        let (filtered, error) = tree.filter_tree_full_path(pattern);
+        return SearchResult {
+            tree: filtered,
+            matches: Vec::new(),
+            error,
+        };
+    }
+
+    let matcher = match Matcher::new(mode, pattern) {
+        Ok(matcher) => matcher,
+        Err(e) => return unfiltered(Some(GUIError::InvalidPattern(short_error(&e)))),
+    };
+    match tree.filter_matching(|node| matcher.matches(node)) {
+        Some((filtered, matches)) => SearchResult {
+            tree: Cow::Owned(filtered),
+            matches,
+            error: None,
+        },
+        None => unfiltered(Some(GUIError::CantFilterTree)),
+    }
+}
+
+/// An entry of a layer that matches a search over all layers
+#[derive(Clone, Debug, PartialEq)]
+pub struct LayerMatch {
+    pub layer: usize,
+    pub path: PathBuf,
+    pub fop: EntryOp,
+}
+
+/// Searches the trees of all layers, in layer order. The path mode looks up the path itself
+/// in every layer rather than filtering the components on the way down.
+pub fn search_layers<'a>(
+    trees: impl IntoIterator<Item = &'a FileTree>,
+    mode: SearchMode,
+    pattern: &str,
+) -> Result<Vec<LayerMatch>, PatternError> {
+    let mut found = Vec::new();
+    if pattern.is_empty() {
+        return Ok(found);
+    }
+    let matcher = Matcher::new(mode, pattern)?;
+    for (layer, tree) in trees.into_iter().enumerate() {
+        let mut push = |node: NodeRef| {
+            found.push(LayerMatch {
+                layer,
+                path: node.path(),
+                fop: node.fop(),
+            })
+        };
+        if mode == SearchMode::Path {
+            if let Some(node) = tree.get(Path::new(pattern)).filter(|node| node.id() != 0) {
+                push(node);
+            }
+        } else {
+            tree.iter().skip(1).filter(|&node| matcher.matches(node)).for_each(push);
+        }
+    }
+    Ok(found)
+}
+
+/// Translates a shell pattern into an anchored regular expression: `*` and `?` stay within
+/// a path component, `**` spans any number of them and `[...]` is a character class.
+/// Patterns for full paths are taken from the root, with or without the leading slash.
+pub fn glob_to_regex(glob: &str, full_path: bool) -> Result<String, PatternError> {
+    let mut regex = String::from("^");
+    if full_path && !glob.starts_with('/') {
+        regex.push_str("/?");
+    }
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    regex.push_str("(?:.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                regex.push('[');
+                if chars.next_if_eq(&'!').is_some() {
+                    regex.push('^');
+                }
+                // Only ranges keep their meaning, everything else in the class is literal
+                loop {
+                    match chars.next() {
+                        None => return Err(PatternError::UnclosedClass),
+                        Some(']') => break,
+                        Some('-') => regex.push('-'),
+                        Some(c) => regex.push_str(&regex::escape(&c.to_string())),
+                    }
+                }
+                regex.push(']');
+            }
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    Ok(regex)
+}
+
+/// Whether the characters of `pattern` appear in `name` in the same order, ignoring case
+fn fuzzy_match(pattern: &[char], name: &str) -> bool {
+    let mut pattern = pattern.iter().peekable();
+    for c in name.chars().flat_map(char::to_lowercase) {
+        if pattern.peek() == Some(&&c) {
+            pattern.next();
+        }
+    }
+    pattern.peek().is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_tree::test_layer;
+    use assert_matches::assert_matches;
+
+    fn glob_matches(glob: &str, path: &str) -> bool {
+        Regex::new(&glob_to_regex(glob, glob.contains('/')).unwrap()).unwrap().is_match(path)
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob_matches("*.so", "libc.so"));
+        assert!(!glob_matches("*.so", "libc.so.6"));
+        assert!(glob_matches("libc.so.?", "libc.so.6"));
+        assert!(glob_matches("usr/**/*.so", "/usr/lib/x86_64-linux-gnu/libssl.so"));
+        assert!(glob_matches("usr/**/*.so", "/usr/libssl.so"));
+        assert!(!glob_matches("usr/*.so", "/usr/lib/libssl.so"));
+        assert!(glob_matches("/etc/**", "/etc/ssl/certs"));
+        assert!(glob_matches("lib[0-9].so", "lib3.so"));
+        assert!(!glob_matches("lib[!0-9].so", "lib3.so"));
+        assert!(glob_matches("a+b(c).txt", "a+b(c).txt"));
+        assert!(glob_matches("[a.b]", "."));
+        assert!(!glob_matches("[a.b]", "x"));
+        assert!(glob_matches("[!a.b]", "x"));
+        assert_matches!(glob_to_regex("[", false), Err(PatternError::UnclosedClass));
+        assert_matches!(glob_to_regex("lib[0-9", false), Err(PatternError::UnclosedClass));
+    }
+
+    fn found(tree: &FileTree, mode: SearchMode, pattern: &str) -> Vec<PathBuf> {
+        let result = search_tree(tree, mode, pattern);
+        assert_matches!(result.error, None);
+        result.matches
+    }
+
+    #[test]
+    fn search_modes() {
+        let tree = test_layer(&[
+            "usr/lib/libssl.so",
+            "usr/lib/libssl.so.3",
+            "app/node_modules/x/package.json",
+            "etc/ssl/openssl.cnf",
+        ]);
+        assert_eq!(
+            found(&tree, SearchMode::Substring, "ssl"),
+            ["/etc/ssl", "/usr/lib/libssl.so", "/usr/lib/libssl.so.3"].map(PathBuf::from)
+        );
+        assert_eq!(found(&tree, SearchMode::Glob, "*.so"), [PathBuf::from("/usr/lib/libssl.so")]);
+        assert_eq!(
+            found(&tree, SearchMode::Glob, "usr/**/*.so*"),
+            ["/usr/lib/libssl.so", "/usr/lib/libssl.so.3"].map(PathBuf::from)
+        );
+        assert_eq!(found(&tree, SearchMode::Regex, r"\.so\.\d+$"), [PathBuf::from("/usr/lib/libssl.so.3")]);
+        assert_eq!(found(&tree, SearchMode::Fuzzy, "ndmod"), [PathBuf::from("/app/node_modules")]);
+
+        let invalid = search_tree(&tree, SearchMode::Regex, "(lib");
+        assert_matches!(invalid.error, Some(GUIError::InvalidPattern(error)) if error == "error: unclosed group");
+        let nothing = search_tree(&tree, SearchMode::Glob, "*.dll");
+        assert_matches!(nothing.error, Some(GUIError::CantFilterTree));
+        assert_eq!(nothing.tree.len(), tree.len());
+    }
+
+    #[test]
+    fn layer_search() {
+        let base = test_layer(&["etc/ssl/certs/ca.pem", "etc/hosts"]);
+        let update = test_layer(&["etc/ssl/certs/extra.pem", "usr/bin/openssl"]);
+        let layers = [&base, &update];
+
+        let certs = search_layers(layers, SearchMode::Path, "/etc/ssl/certs").unwrap();
+        assert_eq!(certs.iter().map(|found| found.layer).collect::<Vec<_>>(), [0, 1]);
+        assert!(certs.iter().all(|found| found.path == Path::new("/etc/ssl/certs")));
+
+        let pems = search_layers(layers, SearchMode::Glob, "*.pem").unwrap();
+        assert_eq!(
+            pems.iter().map(|found| (found.layer, found.path.clone())).collect::<Vec<_>>(),
+            [(0, PathBuf::from("/etc/ssl/certs/ca.pem")), (1, PathBuf::from("/etc/ssl/certs/extra.pem"))]
+        );
+        assert_matches!(search_layers(layers, SearchMode::Path, "/etc/passwd").as_deref(), Ok([]));
+        assert_matches!(search_layers(layers, SearchMode::Regex, "(ssl"), Err(PatternError::Regex(_)));
+    }
+
+    #[test]
+    fn fuzzy() {
+        let pattern: Vec<char> = "nmod".chars().collect();
+        assert!(fuzzy_match(&pattern, "node_modules"));
+        assert!(fuzzy_match(&pattern, "Node_Modules"));
+        assert!(!fuzzy_match(&pattern, "modules_node"));
+    }
+}
@@ -0,0 +1,143 @@
+use crate::capabilities::CAPABILITY_XATTR;
+use crate::docker_image_utils::ImageLayer;
+use crate::file_names::escape_name;
+use crate::file_tree::{EntryType, FileTreeNode, NodeRef, format_size};
+use ratatui::buffer::Buffer;
+use ratatui::layout::Rect;
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, Paragraph, Widget};
+use std::path::PathBuf;
+
+/// Shows the metadata of the entry that is selected in the tree browser
+pub struct DetailsWidget<'a> {
+    node: Option<NodeRef<'a>>,
+    // Layer index and path of the other files with the same content
+    copies: Vec<(usize, PathBuf)>,
+}
+
+impl<'a> DetailsWidget<'a> {
+    pub fn new(node: Option<NodeRef<'a>>, copies: Vec<(usize, PathBuf)>) -> Self {
+        DetailsWidget { node, copies }
+    }
+
+    fn describe_copies(&self) -> String {
+        match self.copies.first() {
+            None => "-".to_string(),
+            Some((layer, path)) if self.copies.len() == 1 => {
+                format!("layer {} {}", ImageLayer::label(*layer), escape_name(path.as_os_str()))
+            }
+            Some((layer, path)) => format!(
+                "layer {} {} (+{} more)",
+                ImageLayer::label(*layer),
+                escape_name(path.as_os_str()),
+                self.copies.len() - 1
+            ),
+        }
+    }
+}
+
+fn describe_type(ftype: &EntryType) -> String {
+    match ftype {
+        EntryType::Directory => "directory".to_string(),
+        EntryType::File => "file".to_string(),
+        EntryType::Symlink(target) => format!("symlink to {}", escape_name(target.as_os_str())),
+        EntryType::Hardlink(target) => format!("hard link to {}", escape_name(target.as_os_str())),
+        EntryType::CharDevice { major, minor } => {
+            format!("character device {}, {}", major, minor)
+        }
+        EntryType::BlockDevice { major, minor } => format!("block device {}, {}", major, minor),
+        EntryType::Fifo => "fifo".to_string(),
+        EntryType::Socket => "socket".to_string(),
+        EntryType::Badfile => "unknown".to_string(),
+    }
+}
+
+/// Names of the extended attributes, with the capabilities spelled out
+fn describe_xattrs(node: &FileTreeNode) -> String {
+    if node.xattrs().is_empty() {
+        return "-".to_string();
+    }
+    node.xattrs()
+        .keys()
+        .map(|name| match node.capabilities() {
+            Some(capabilities) if name == CAPABILITY_XATTR => {
+                format!("{} ({})", name, capabilities)
+            }
+            _ => name.clone(),
+        })
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// Formats seconds since the epoch as a UTC date, e.g. `2025-03-26 15:19:49 UTC`
+fn format_timestamp(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let seconds = timestamp % 86400;
+
+    // Civil date from days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+impl Widget for DetailsWidget<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let lines: Vec<Line> = match &self.node {
+            None => vec![Line::from("Nothing selected")],
+            Some(node) => {
+                vec![
+                    Line::from(format!("Path:     {}", escape_name(node.path().as_os_str()))),
+                    Line::from(format!("Type:     {}", describe_type(&node.ftype()))),
+                    Line::from(format!(
+                        "Mode:     {:04o} ({})",
+                        node.mode(),
+                        node.permissions()
+                    )),
+                    Line::from(format!("Owner:    {}:{}", node.uid(), node.gid())),
+                    Line::from(format!("Size:     {}", format_size(node.size()))),
+                    Line::from(format!("Modified: {}", format_timestamp(node.mtime()))),
+                    Line::from(format!("Content:  {}", node.content_hash().unwrap_or("-"))),
+                    Line::from(format!("Copies:   {}", self.describe_copies())),
+                    Line::from(format!("Xattrs:   {}", describe_xattrs(node))),
+                ]
+            }
+        };
+
+        let details = Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title("Details"));
+        details.render(area, buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_timestamp(951782400), "2000-02-29 00:00:00 UTC");
+        assert_eq!(format_timestamp(1743002389), "2025-03-26 15:19:49 UTC");
+    }
+
+    #[test]
+    fn copies_use_layer_labels() {
+        let copies = vec![(0, PathBuf::from("/bin/busybox")), (3, PathBuf::from("/bin/sh"))];
+        assert_eq!(DetailsWidget::new(None, copies).describe_copies(), "layer #0 /bin/busybox (+1 more)");
+    }
+}
@@ -0,0 +1,70 @@
+use crate::docker_image_utils::ImageLayer;
+use crate::efficiency::Efficiency;
+use crate::file_names::escape_name;
+use crate::file_tree::format_size;
+use ratatui::buffer::Buffer;
+use ratatui::layout::Rect;
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, Paragraph, Widget};
+
+/// Summary of the space the image wastes, with the paths that waste the most
+pub struct EfficiencyWidget<'a> {
+    efficiency: &'a Efficiency,
+}
+
+impl<'a> EfficiencyWidget<'a> {
+    pub fn new(efficiency: &'a Efficiency) -> Self {
+        EfficiencyWidget { efficiency }
+    }
+}
+
+fn score_color(score: f64) -> Color {
+    if score >= 0.95 {
+        Color::Green
+    } else if score >= 0.8 {
+        Color::Yellow
+    } else {
+        Color::Red
+    }
+}
+
+impl<'a> Widget for EfficiencyWidget<'a> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let efficiency = self.efficiency;
+        let score = efficiency.score();
+        let mut lines = vec![
+            Line::from(vec![
+                Span::styled(
+                    format!("Efficiency {:.1}%", score * 100.0),
+                    Style::new().fg(score_color(score)).add_modifier(Modifier::BOLD),
+                ),
+                Span::raw(format!(
+                    "  stored {}, wasted {}, duplicated {}",
+                    format_size(efficiency.total_bytes),
+                    format_size(efficiency.wasted_bytes),
+                    format_size(efficiency.duplicate_bytes())
+                )),
+            ]),
+            Line::styled("Wasted by, written in -> dropped in layer", Style::new().fg(Color::DarkGray)),
+        ];
+        // Borders and the two lines above
+        let rows = (area.height as usize).saturating_sub(4);
+        for wasted in efficiency.wasted_paths.iter().take(rows) {
+            let written_by: Vec<String> = wasted.written_by.iter().copied().map(ImageLayer::label).collect();
+            let dropped_by = wasted.dropped_by.map(ImageLayer::label).unwrap_or_default();
+            lines.push(Line::from(vec![
+                Span::styled(format!("{:>9}", format_size(wasted.wasted)), Style::new().fg(Color::Red)),
+                Span::raw(format!("  {} -> {}  ", written_by.join(","), dropped_by)),
+                Span::raw(escape_name(wasted.path.as_os_str())),
+            ]));
+        }
+        if efficiency.wasted_paths.is_empty() {
+            lines.push(Line::raw("No layer deletes or overwrites what a lower one added"));
+        }
+
+        Paragraph::new(lines)
+            .block(Block::default().borders(Borders::ALL).title("Efficiency"))
+            .render(area, buf);
+    }
+}
@@ -12,10 +12,28 @@ pub fn draw_help(f: &mut Frame, area: Rect) {
         Line::from("Use arrows to move around tree and list"),
         Line::from("Press 'TAB' to switch between tree and list"),
         Line::from("Press 'SPACEBAR' to open or close the tree item (directory)"),
+        Line::from(
+            "Press 'a' to switch between the changes of the layer, the aggregated filesystem up to it and the blame view",
+        ),
+        Line::from(
+            "Press 'g' on a symlink to jump to its target; dangling links are marked in red",
+        ),
+        Line::from(
+            "Press 'm' to mark two layers and 'd' to see what changed between their merged filesystems",
+        ),
         Line::from(
             "Press 'CTRL+f' to open search bar and filter the tree for the items that interest you",
         ),
-        Line::from("Press 'q' to quit the app"),
+        Line::from(
+            "In the search bar, press 'TAB' to switch between path, substring, glob, regex and fuzzy search",
+        ),
+        Line::from(
+            "Press 'l' to search all layers for a path and jump to the layers that add, change or remove it",
+        ),
+        Line::from(
+            "Press 'H' on an entry to see every layer that added, changed or removed it, with the command that did it",
+        ),
+        Line::from("Press 'q' or 'CTRL+c' to quit the app"),
         Line::from("Press 'h' to show this help"),
     ];
 
@@ -1,8 +1,13 @@
+use crate::docker_image_utils::ImageLayer;
+use crate::file_tree::OpCounts;
 use crate::widgets::navigation_traits::{WidgetNav, WidgetNavBounds};
 use ratatui::buffer::Buffer;
 use ratatui::layout::{Constraint, Direction, Layout, Rect};
 use ratatui::style::{Color, Modifier, Style};
-use ratatui::widgets::{Block, Borders, List, ListState, Paragraph, StatefulWidget, Widget};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{
+    Block, Borders, List, ListItem, ListState, Paragraph, StatefulWidget, Widget,
+};
 
 use super::focus_traits::WidgetFocusTrait;
 
@@ -10,18 +15,30 @@ pub struct LayerBrowserWidgetState {
     title: String,
     is_toggled: bool,
     pub state: ListState,
+    // Layers marked to be compared, at most two, in the order they were marked
+    marked: Vec<usize>,
 }
 
 pub struct LayerBrowserWidget<'a> {
     layer_names: &'a Vec<String>,
     layer_commands: &'a Vec<String>,
+    // Layers that failed to load are shown as error placeholders
+    layer_failed: &'a Vec<bool>,
+    layer_op_counts: &'a Vec<OpCounts>,
 }
 
 impl<'a> LayerBrowserWidget<'a> {
-    pub fn new(layer_names: &'a Vec<String>, layer_commands: &'a Vec<String>) -> Self {
+    pub fn new(
+        layer_names: &'a Vec<String>,
+        layer_commands: &'a Vec<String>,
+        layer_failed: &'a Vec<bool>,
+        layer_op_counts: &'a Vec<OpCounts>,
+    ) -> Self {
         Self {
             layer_names,
             layer_commands,
+            layer_failed,
+            layer_op_counts,
         }
     }
 }
@@ -34,6 +51,7 @@ impl LayerBrowserWidgetState {
             title: "Layer Browser".to_string(),
             is_toggled: true,
             state,
+            marked: Vec::new(),
         }
     }
 
@@ -44,6 +62,30 @@ impl LayerBrowserWidgetState {
     pub fn selected(&self) -> Option<usize> {
         self.state.selected()
     }
+
+    /// Marks the selected layer for a diff, or unmarks it. Marking a third layer drops the
+    /// one marked first.
+    pub fn toggle_mark(&mut self) {
+        let Some(selected) = self.selected() else {
+            return;
+        };
+        if let Some(position) = self.marked.iter().position(|&marked| marked == selected) {
+            self.marked.remove(position);
+            return;
+        }
+        if self.marked.len() == 2 {
+            self.marked.remove(0);
+        }
+        self.marked.push(selected);
+    }
+
+    /// The two marked layers, the lower one first
+    pub fn marked_pair(&self) -> Option<(usize, usize)> {
+        match self.marked[..] {
+            [a, b] => Some((a.min(b), a.max(b))),
+            _ => None,
+        }
+    }
 }
 
 impl WidgetNav for LayerBrowserWidgetState {
@@ -71,7 +113,7 @@ impl<'a> WidgetNavBounds<LayerBrowserWidgetState> for LayerBrowserWidget<'a> {
         let max = self.layer_names.len();
         if let Some(selected) = state.state.selected() {
             if selected >= max {
-                state.state.select(Some(max - 1));
+                state.state.select(max.checked_sub(1));
             }
         }
     }
@@ -102,7 +144,38 @@ impl<'a> StatefulWidget for LayerBrowserWidget<'a> {
             .split(area);
 
         // Create the List widget
-        let list = List::new(self.layer_names.clone())
+        let items: Vec<ListItem> = self
+            .layer_names
+            .iter()
+            .zip(self.layer_failed.iter())
+            .zip(self.layer_op_counts.iter())
+            .enumerate()
+            .map(|(index, ((name, &failed), counts))| {
+                let mark = if state.marked.contains(&index) {
+                    Span::styled("* ", Style::default().fg(Color::Magenta).add_modifier(Modifier::BOLD))
+                } else {
+                    Span::raw("")
+                };
+                // Other views refer to layers by this label
+                let label = Span::raw(format!("{:<4} ", ImageLayer::label(index)));
+                if failed {
+                    ListItem::new(Line::from(vec![mark, label, Span::raw(format!("{} (failed to load)", name))]))
+                        .style(Style::default().fg(Color::Red))
+                } else {
+                    ListItem::new(Line::from(vec![
+                        mark,
+                        label,
+                        Span::raw(format!("{} ", name)),
+                        Span::styled(format!("+{}", counts.added), Style::default().fg(Color::Green)),
+                        Span::raw(" "),
+                        Span::styled(format!("~{}", counts.modified), Style::default().fg(Color::Yellow)),
+                        Span::raw(" "),
+                        Span::styled(format!("-{}", counts.removed), Style::default().fg(Color::Red)),
+                    ]))
+                }
+            })
+            .collect();
+        let list = List::new(items)
             .block(
                 Block::default()
                     .borders(Borders::ALL)
@@ -120,10 +193,15 @@ impl<'a> StatefulWidget for LayerBrowserWidget<'a> {
         StatefulWidget::render(list, layout[0], buf, &mut state.state);
 
         // Create the Command widget
-        let command: Paragraph =
-            Paragraph::new(self.layer_commands[state.state.selected().unwrap_or(0)].clone())
-                .block(Block::default().borders(Borders::ALL).title("Command"))
-                .wrap(ratatui::widgets::Wrap { trim: true });
+        let command_text = state
+            .state
+            .selected()
+            .and_then(|selected| self.layer_commands.get(selected))
+            .cloned()
+            .unwrap_or_default();
+        let command: Paragraph = Paragraph::new(command_text)
+            .block(Block::default().borders(Borders::ALL).title("Command"))
+            .wrap(ratatui::widgets::Wrap { trim: true });
 
         Widget::render(command, layout[1], buf);
     }
@@ -0,0 +1,162 @@
+use ratatui::buffer::Buffer;
+use ratatui::layout::{Constraint, Direction, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, Clear, List, ListItem, ListState, Paragraph, StatefulWidget, Widget};
+
+use crate::docker_image_utils::ImageLayer;
+use crate::file_names::escape_name;
+use crate::file_tree::EntryOp;
+use crate::search::{LayerMatch, PatternError, SearchMode, short_error};
+use crate::widgets::centered;
+use crate::widgets::navigation_traits::WidgetNav;
+
+/// A popup that searches every layer and lists the ones that touch the matching paths
+pub struct LayerSearchWidgetState {
+    search_string: String,
+    mode: SearchMode,
+    // Shown under the input instead of results
+    error: Option<String>,
+    results: Vec<LayerMatch>,
+    list: ListState,
+}
+
+pub struct LayerSearchWidget<'a> {
+    layer_names: &'a [String],
+}
+
+impl<'a> LayerSearchWidget<'a> {
+    pub fn new(layer_names: &'a [String]) -> Self {
+        LayerSearchWidget { layer_names }
+    }
+}
+
+impl LayerSearchWidgetState {
+    pub fn new() -> Self {
+        LayerSearchWidgetState {
+            search_string: String::new(),
+            mode: SearchMode::Path,
+            error: None,
+            results: Vec::new(),
+            list: ListState::default(),
+        }
+    }
+
+    /// Starts over from the search of the tree
+    pub fn reset(&mut self, search_string: &str, mode: SearchMode) {
+        self.search_string = search_string.to_string();
+        self.mode = mode;
+    }
+
+    pub fn push_c(&mut self, ch: char) {
+        self.search_string.push(ch);
+    }
+
+    pub fn pop_c(&mut self) {
+        self.search_string.pop();
+    }
+
+    pub fn get(&self) -> String {
+        self.search_string.clone()
+    }
+
+    pub fn mode(&self) -> SearchMode {
+        self.mode
+    }
+
+    pub fn next_mode(&mut self) {
+        self.mode = self.mode.next();
+    }
+
+    pub fn set_results(&mut self, results: Result<Vec<LayerMatch>, PatternError>) {
+        match results {
+            Ok(results) => {
+                self.error = None;
+                self.list.select(if results.is_empty() { None } else { Some(0) });
+                self.results = results;
+            }
+            Err(error) => {
+                self.error = Some(short_error(&error));
+                self.list.select(None);
+                self.results.clear();
+            }
+        }
+    }
+
+    pub fn selected(&self) -> Option<&LayerMatch> {
+        self.list.selected().and_then(|selected| self.results.get(selected))
+    }
+}
+
+impl WidgetNav for LayerSearchWidgetState {
+    fn next(&mut self) {
+        if let Some(selected) = self.list.selected() {
+            if selected + 1 < self.results.len() {
+                self.list.select(Some(selected + 1));
+            }
+        }
+    }
+
+    fn prev(&mut self) {
+        if let Some(selected) = self.list.selected() {
+            self.list.select(Some(selected.saturating_sub(1)));
+        }
+    }
+}
+
+impl<'a> StatefulWidget for LayerSearchWidget<'a> {
+    type State = LayerSearchWidgetState;
+
+    fn render(self, area: Rect, buf: &mut Buffer, state: &mut Self::State) {
+        let area = centered(area, 80, 70);
+        Clear.render(area, buf);
+        let layout = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Length(3), Constraint::Fill(1)].as_ref())
+            .split(area);
+
+        let mut input_block = Block::default()
+            .borders(Borders::ALL)
+            .border_style(Style::default().fg(Color::Yellow))
+            .title(format!("Search all layers [{}, TAB to change]", state.mode.title()));
+        if let Some(error) = &state.error {
+            input_block = input_block.title_bottom(Line::styled(error.clone(), Style::new().fg(Color::Red)));
+        }
+        Paragraph::new(state.search_string.clone() + "█")
+            .block(input_block)
+            .render(layout[0], buf);
+
+        let items: Vec<ListItem> = state
+            .results
+            .iter()
+            .map(|found| {
+                let name = self.layer_names.get(found.layer).map(String::as_str).unwrap_or_default();
+                let op_style = match found.fop {
+                    EntryOp::Add => Style::new().fg(Color::Green),
+                    EntryOp::Modify => Style::new().fg(Color::Yellow),
+                    EntryOp::Remove => Style::new().fg(Color::Red),
+                };
+                ListItem::new(Line::from(vec![
+                    Span::raw(format!("{:>4} {} ", ImageLayer::label(found.layer), name)),
+                    Span::styled(format!("{:<7}", found.fop.to_string()), op_style),
+                    Span::raw(escape_name(found.path.as_os_str())),
+                ]))
+            })
+            .collect();
+        let list = List::new(items)
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .border_style(Style::default().fg(Color::Yellow))
+                    .title(format!("{} matches, ENTER to jump, ESC to close", state.results.len())),
+            )
+            .highlight_style(
+                Style::default()
+                    .bg(Color::Blue)
+                    .fg(Color::White)
+                    .add_modifier(Modifier::BOLD),
+            )
+            .highlight_symbol(">> ");
+        StatefulWidget::render(list, layout[1], buf, &mut state.list);
+    }
+}
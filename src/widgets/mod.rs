@@ -1,8 +1,33 @@
+pub mod details_widget;
+pub mod efficiency_widget;
 pub mod focus_traits;
 pub mod help_widget;
 pub mod layer_browser_widget;
+pub mod layer_search_widget;
 pub mod multitree_browser_widget;
 pub mod navigation_traits;
+pub mod path_history_widget;
 pub mod searchbar;
 pub mod tree_browser_widget;
 
+use ratatui::layout::{Constraint, Direction, Layout, Rect};
+
+/// Centered area of the given share of `area`, in percent
+pub fn centered(area: Rect, width: u16, height: u16) -> Rect {
+    let vertical = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Percentage((100 - height) / 2),
+            Constraint::Percentage(height),
+            Constraint::Fill(1),
+        ])
+        .split(area);
+    Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([
+            Constraint::Percentage((100 - width) / 2),
+            Constraint::Percentage(width),
+            Constraint::Fill(1),
+        ])
+        .split(vertical[1])[1]
+}
@@ -1,54 +1,131 @@
+
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
 use ratatui::widgets::{Block, Borders, Widget};
 use ratatui::{buffer::Buffer, layout::Rect, widgets::StatefulWidget};
 
-use crate::docker_image_utils::ImageLayer;
-use crate::widgets::tree_browser_widget::{TreeBrowserWidget, TreeBrowserWidgetState};
+use crate::file_tree::{FileTree, PathChange, format_size_delta};
+use crate::search::SearchMode;
+use crate::widgets::tree_browser_widget::{Annotations, TreeBrowserWidget, TreeBrowserWidgetState};
 use crate::widgets::navigation_traits::WidgetNav;
 
 use crate::widgets::focus_traits::WidgetFocusTrait;
 
+/// What the tree browser shows for the selected layer
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum TreeViewMode {
+    /// Only the changes introduced by the layer
+    LayerChanges,
+    /// The filesystem as it looks after all layers up to and including the selected one
+    Aggregated,
+    /// The final filesystem, every entry annotated with the layer that last wrote it
+    Blame,
+    /// What changed between the merged filesystems of two marked layers
+    Diff,
+}
+
+impl TreeViewMode {
+    pub fn toggled(self) -> TreeViewMode {
+        match self {
+            TreeViewMode::LayerChanges => TreeViewMode::Aggregated,
+            TreeViewMode::Aggregated => TreeViewMode::Blame,
+            // The diff is only shown on demand, leaving it goes back to the start
+            TreeViewMode::Blame | TreeViewMode::Diff => TreeViewMode::LayerChanges,
+        }
+    }
+
+    fn title(self) -> &'static str {
+        match self {
+            TreeViewMode::LayerChanges => "layer changes",
+            TreeViewMode::Aggregated => "aggregated filesystem",
+            TreeViewMode::Blame => "blame, final filesystem",
+            TreeViewMode::Diff => "diff of the marked layers",
+        }
+    }
+}
+
 pub struct MultiTreeBrowserWidgetState {
     pub search_string: String,
-    pub current_layer: String,
+    pub search_mode: SearchMode,
+    pub current_layer: usize,
+    pub view_mode: TreeViewMode,
     title: String,
     is_toggled: bool,
-    tree_states: HashMap<String, TreeBrowserWidgetState >,
+    // Every layer in every view mode keeps its own selection and expanded directories
+    tree_states: HashMap<(TreeViewMode, usize), TreeBrowserWidgetState>,
 }
 
 impl MultiTreeBrowserWidgetState {
-    pub fn new(search_string: &str, layer_names: &[String]) -> Self {
-        let mut tree_states: HashMap<String, TreeBrowserWidgetState > = HashMap::new();
-        for layer_name in layer_names {
-            tree_states.insert(layer_name.to_string(), TreeBrowserWidgetState::new(search_string));
-        }
-
+    pub fn new(search_string: &str) -> Self {
         MultiTreeBrowserWidgetState {
             search_string: search_string.to_string(),
-            tree_states: tree_states,
-            current_layer: "".to_string(),
+            search_mode: SearchMode::Path,
+            tree_states: HashMap::new(),
+            current_layer: 0,
+            view_mode: TreeViewMode::LayerChanges,
             title: "Filetree Browser".to_string(),
             is_toggled: false,
         }
     }
-    
-    pub fn expand(&mut self) {
-        let cur_selected_state = self.tree_states.get_mut(&self.current_layer);
-        if let Some(selected_state) = cur_selected_state {
-            selected_state.expand();
-        } else {
-            return;
+
+    // The blame and diff views show the same tree whatever layer is selected
+    fn current_key(&self) -> (TreeViewMode, usize) {
+        match self.view_mode {
+            TreeViewMode::Blame | TreeViewMode::Diff => (self.view_mode, 0),
+            view_mode => (view_mode, self.current_layer),
         }
     }
 
-    pub fn set_search_string(&mut self, search_string: &str) {
+    fn current_state(&mut self) -> &mut TreeBrowserWidgetState {
+        let key = self.current_key();
+        let (search_string, search_mode) = (&self.search_string, self.search_mode);
+        self.tree_states
+            .entry(key)
+            .or_insert_with(|| TreeBrowserWidgetState::new(search_string, search_mode))
+    }
+
+    pub fn expand(&mut self) {
+        self.current_state().expand();
+    }
+
+    pub fn selected_path(&self) -> Option<PathBuf> {
+        self.tree_states
+            .get(&self.current_key())
+            .and_then(|state| state.selected_path())
+    }
+
+    pub fn search_failed(&self) -> bool {
+        self.tree_states
+            .get(&self.current_key())
+            .is_some_and(|state| state.search_failed())
+    }
+
+    pub fn pattern_error(&self) -> Option<String> {
+        self.tree_states
+            .get(&self.current_key())
+            .and_then(|state| state.pattern_error())
+            .map(str::to_string)
+    }
+
+    /// Drops the selection, expanded directories and search results of a view mode whose
+    /// tree was replaced, e.g. the diff when other layers are marked
+    pub fn forget(&mut self, view_mode: TreeViewMode) {
+        self.tree_states.retain(|(mode, _), _| *mode != view_mode);
+    }
+
+    pub fn select_path(&mut self, path: &Path) {
+        self.current_state().select_path(path);
+    }
+
+    pub fn set_search(&mut self, search_string: &str, search_mode: SearchMode) {
         self.search_string = search_string.to_string();
+        self.search_mode = search_mode;
         for (_, state) in self.tree_states.iter_mut() {
-            state.set_search_string(search_string);
+            state.set_search(search_string, search_mode);
         }
     }
-} 
+}
 
 impl WidgetFocusTrait for MultiTreeBrowserWidgetState {
     fn focus_on(&mut self, selected: bool) {
@@ -68,42 +145,32 @@ impl WidgetFocusTrait for MultiTreeBrowserWidgetState {
 
 impl WidgetNav for MultiTreeBrowserWidgetState {
     fn next(&mut self) {
-        let cur_selected_state = self.tree_states.get_mut(&self.current_layer);
-        if let Some(selected_state) = cur_selected_state {
-            // selected_state.select_relative(|current| {
-                // current.map_or(0, |current| current.saturating_add(1))
-            // });
-            selected_state.next();
-        } else {
-            return;
-        }
+        self.current_state().next();
     }
 
     fn prev(&mut self) {
-        let cur_selected_state = self.tree_states.get_mut(&self.current_layer);
-        if let Some(selected_state) = cur_selected_state {
-            selected_state.prev();
-        } else {
-            return;
-        }
+        self.current_state().prev();
     }
 }
 
 pub struct MultiTreeBrowserWidget<'a> {
-    tree_layers: HashMap<String, &'a ImageLayer >,
+    // Trees that can be shown, by layer index; trees of the current view mode only
+    trees: HashMap<usize, &'a FileTree>,
+    // Merged filesystem of the current layer, symlinks are resolved in it
+    filesystem: Option<&'a FileTree>,
+    annotations: Annotations<'a>,
 }
 
 impl<'a> MultiTreeBrowserWidget<'a> {
-    pub fn new(layers: &'a [ImageLayer]) -> Self {
-        
-        let mut map: HashMap<String, &'a ImageLayer > = HashMap::new();
-        for layer in layers.iter() {
-            let name = layer.name.clone();
-            map.insert(name, layer);
-        }
-
+    pub fn new(
+        trees: HashMap<usize, &'a FileTree>,
+        filesystem: Option<&'a FileTree>,
+        annotations: Annotations<'a>,
+    ) -> Self {
         MultiTreeBrowserWidget {
-            tree_layers: map,
+            trees,
+            filesystem,
+            annotations,
         }
     }
 }
@@ -111,24 +178,31 @@ impl<'a> MultiTreeBrowserWidget<'a> {
 impl<'a> StatefulWidget for MultiTreeBrowserWidget<'a> {
     type State = MultiTreeBrowserWidgetState;
 
-    fn render(self, area: Rect, buf: & mut Buffer, state: & mut Self::State) { 
-        let cur_layer = &state.current_layer;
-        if !self.tree_layers.keys().any(|k| k == cur_layer) {
+    fn render(self, area: Rect, buf: & mut Buffer, state: & mut Self::State) {
+        let Some(&cur_tree) = self.trees.get(&state.current_layer) else {
             return;
-        }
-        let cur_tree_state = state.tree_states.get_mut(cur_layer).expect("Can't find current layer in state");
-        let &cur_tree_layer = self.tree_layers.get(cur_layer).expect("Can't find current layer in widget");
-        let tree_widget = TreeBrowserWidget::new(cur_tree_layer);
+        };
+        let tree_widget = TreeBrowserWidget::new(cur_tree, self.filesystem, self.annotations);
 
-        let block = Block::default()
+        let mut block = Block::default()
             .borders(Borders::ALL)
-            .title(state.title.clone());        
+            .title(format!("{} ({})", state.title, state.view_mode.title()));
+        if let Annotations::Diff(diff) = self.annotations {
+            let counts = diff.counts();
+            let count = |change| counts.get(&change).copied().unwrap_or_default();
+            block = block.title_bottom(format!(
+                "+{} ~{} metadata {} -{}, {} in total",
+                count(PathChange::Added),
+                count(PathChange::Modified),
+                count(PathChange::Metadata),
+                count(PathChange::Removed),
+                format_size_delta(diff.size_delta())
+            ));
+        }
         let inner_area = block.inner(area);
 
         block.render(area, buf);
 
-
-
-        StatefulWidget::render(tree_widget, inner_area, buf, cur_tree_state);
+        StatefulWidget::render(tree_widget, inner_area, buf, state.current_state());
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,136 @@
+use ratatui::buffer::Buffer;
+use ratatui::layout::Rect;
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span, Text};
+use ratatui::widgets::{Block, Borders, Clear, List, ListItem, ListState, StatefulWidget, Widget};
+use std::path::PathBuf;
+
+use crate::docker_image_utils::ImageLayer;
+use crate::file_names::escape_name;
+use crate::file_tree::{PathChange, format_size};
+use crate::history::HistoryEntry;
+use crate::widgets::centered;
+use crate::widgets::navigation_traits::WidgetNav;
+
+/// A popup with every layer that touched a path, like `git log` for a file
+pub struct PathHistoryWidgetState {
+    path: PathBuf,
+    entries: Vec<HistoryEntry>,
+    list: ListState,
+}
+
+pub struct PathHistoryWidget<'a> {
+    layers: &'a [ImageLayer],
+}
+
+impl<'a> PathHistoryWidget<'a> {
+    pub fn new(layers: &'a [ImageLayer]) -> Self {
+        PathHistoryWidget { layers }
+    }
+}
+
+impl PathHistoryWidgetState {
+    pub fn new() -> Self {
+        PathHistoryWidgetState {
+            path: PathBuf::new(),
+            entries: Vec::new(),
+            list: ListState::default(),
+        }
+    }
+
+    /// Shows the history of `path`, starting from the last change
+    pub fn set(&mut self, path: PathBuf, entries: Vec<HistoryEntry>) {
+        self.list.select(entries.len().checked_sub(1));
+        self.path = path;
+        self.entries = entries;
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    pub fn selected(&self) -> Option<&HistoryEntry> {
+        self.list.selected().and_then(|selected| self.entries.get(selected))
+    }
+}
+
+impl WidgetNav for PathHistoryWidgetState {
+    fn next(&mut self) {
+        if let Some(selected) = self.list.selected() {
+            if selected + 1 < self.entries.len() {
+                self.list.select(Some(selected + 1));
+            }
+        }
+    }
+
+    fn prev(&mut self) {
+        if let Some(selected) = self.list.selected() {
+            self.list.select(Some(selected.saturating_sub(1)));
+        }
+    }
+}
+
+impl<'a> StatefulWidget for PathHistoryWidget<'a> {
+    type State = PathHistoryWidgetState;
+
+    fn render(self, area: Rect, buf: &mut Buffer, state: &mut Self::State) {
+        let area = centered(area, 80, 70);
+        Clear.render(area, buf);
+
+        let items: Vec<ListItem> = state
+            .entries
+            .iter()
+            .map(|entry| {
+                let layer = self.layers.get(entry.layer);
+                let name = layer.map(|layer| layer.name.as_str()).unwrap_or_default();
+                let change_style = match entry.change {
+                    PathChange::Added => Style::new().fg(Color::Green),
+                    PathChange::Modified => Style::new().fg(Color::Yellow),
+                    PathChange::Metadata => Style::new().fg(Color::Cyan),
+                    PathChange::Removed => Style::new().fg(Color::Red),
+                };
+                let mut header = vec![
+                    Span::raw(format!("{:>4} {} ", ImageLayer::label(entry.layer), name)),
+                    Span::styled(format!("{:<9}", entry.change.to_string()), change_style),
+                ];
+                if let Some(data) = &entry.data {
+                    header.push(Span::raw(format!(
+                        "{:>9}  {} {}:{}",
+                        format_size(data.size()),
+                        data.permissions(),
+                        data.uid(),
+                        data.gid()
+                    )));
+                    if let Some(hash) = data.content_hash() {
+                        header.push(Span::styled(format!("  {}", hash), Style::new().fg(Color::DarkGray)));
+                    }
+                }
+                // The command is what explains the change; long ones are cut by the popup
+                let command = layer
+                    .map(|layer| layer.short_command())
+                    .unwrap_or_default();
+                ListItem::new(Text::from(vec![
+                    Line::from(header),
+                    Line::styled(format!("      {}", command), Style::new().fg(Color::DarkGray)),
+                ]))
+            })
+            .collect();
+
+        let title = if state.entries.is_empty() {
+            format!("History of {}: no layer touches it", escape_name(state.path.as_os_str()))
+        } else {
+            format!("History of {}", escape_name(state.path.as_os_str()))
+        };
+        let list = List::new(items)
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .border_style(Style::default().fg(Color::Yellow))
+                    .title(title)
+                    .title_bottom("ENTER to go to the layer, ESC to close"),
+            )
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+            .highlight_symbol(">> ");
+        StatefulWidget::render(list, area, buf, &mut state.list);
+    }
+}
@@ -1,20 +1,29 @@
 use ratatui::buffer::Buffer;
 use ratatui::layout::Rect;
+use ratatui::style::{Color, Style};
+use ratatui::text::Line;
 use ratatui::widgets::{Block, Borders, Paragraph, StatefulWidget, Widget};
 
 use super::focus_traits::WidgetFocusTrait;
+use crate::search::SearchMode;
 
 pub struct SearchBarWidgetState {
     title: String,
     is_toggled: bool,
     search_string: String,
+    mode: SearchMode,
+    // Why the search string can't be used, e.g. an invalid regex; a single line
+    error: Option<String>,
 }
 
-pub struct SearchBarWidget {}
+pub struct SearchBarWidget {
+    // The search is valid, but nothing in the tree matches it
+    nothing_found: bool,
+}
 
 impl SearchBarWidget {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(nothing_found: bool) -> Self {
+        Self { nothing_found }
     }
 
     // Simple logic for blinking - you might want something more sophisticated
@@ -34,6 +43,8 @@ impl SearchBarWidgetState {
             title: "Search".to_string(),
             is_toggled: false,
             search_string: "".to_string(),
+            mode: SearchMode::Path,
+            error: None,
         }
     }
 
@@ -48,6 +59,23 @@ impl SearchBarWidgetState {
     pub fn get(&self) -> String {
         self.search_string.clone()
     }
+
+    pub fn set(&mut self, search_string: &str, mode: SearchMode) {
+        self.search_string = search_string.to_string();
+        self.mode = mode;
+    }
+
+    pub fn mode(&self) -> SearchMode {
+        self.mode
+    }
+
+    pub fn next_mode(&mut self) {
+        self.mode = self.mode.next();
+    }
+
+    pub fn set_error(&mut self, error: Option<String>) {
+        self.error = error;
+    }
 }
 
 impl WidgetFocusTrait for SearchBarWidgetState {
@@ -83,11 +111,15 @@ impl StatefulWidget for SearchBarWidget {
             }
         };
 
-        let search = Paragraph::new(search_string).block(
-            Block::default()
-                .borders(Borders::ALL)
-                .title(state.title.clone()),
-        );
+        let mut block = Block::default()
+            .borders(Borders::ALL)
+            .title(format!("{} [{}, TAB to change]", state.title, state.mode.title()));
+        if let Some(error) = &state.error {
+            block = block.title_bottom(Line::styled(error.clone(), Style::new().fg(Color::Red)));
+        } else if self.nothing_found {
+            block = block.title_bottom(Line::styled("nothing matches", Style::new().fg(Color::Red)));
+        }
+        let search = Paragraph::new(search_string).block(block);
         Widget::render(search, area, buf);
     }
 }
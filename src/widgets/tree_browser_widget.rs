@@ -1,109 +1,189 @@
 use super::navigation_traits::WidgetNav;
 use crate::docker_image_utils::ImageLayer;
 use crate::exceptions::GUIError;
-use crate::file_tree::FileTreeNode;
-use crate::file_tree::{EntryOp, EntryType};
+use crate::file_names::escape_name;
+use crate::file_tree::{FileTree, LinkTarget, NodeRef};
+use crate::history::Blame;
+use crate::search::{SearchMode, search_tree};
+use crate::file_tree::{DiffEntry, EntryOp, EntryType, PathChange, TreeDiff, format_size, format_size_delta};
 use ratatui::buffer::Buffer;
 use ratatui::layout::Rect;
 use ratatui::style::{Color, Modifier, Style};
-use ratatui::text::Text;
-use ratatui::widgets::StatefulWidget;
-use std::cell::RefCell;
-use std::collections::HashMap;
-use std::path::PathBuf;
-use std::rc::Rc;
+use ratatui::text::{Line, Span, Text};
+use ratatui::widgets::{Paragraph, StatefulWidget, Widget};
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
 use tui_tree_widget::{Tree, TreeItem, TreeState};
 
+/// What is shown next to the entries besides their own data
+#[derive(Clone, Copy)]
+pub enum Annotations<'a> {
+    None,
+    /// The layer that last wrote each entry, with the layers to describe it
+    Blame(&'a Blame, &'a [ImageLayer]),
+    /// How each entry changed, the tree is the one of the diff
+    Diff(&'a TreeDiff),
+}
+
+/// `filesystem` is the merged filesystem the symlinks of the tree are resolved in
 fn construct_items<'a>(
-    layer: &'a ImageLayer,
-    filter_str: &'a str,
-) -> (Vec<TreeItem<'a, PathBuf>>, Option<GUIError>) {
-    // TODO:: think why this compiles without reference
-    let tree = &layer.tree;
-    // let filtered_tree = tree.filter_tree_full_path(filter_str);
-    // todo!("Implement the filter_tree_full_path function");
-    // let tree = if let Some(filt_tree) = filtered_tree.as_ref() {
-    //     filt_tree // Reference to the filtered tree
-    // } else {
-    //     &layer.tree // Reference to the original tree
-    // };
-    let (tree, error) = tree.filter_tree_full_path(filter_str);
-
-    // parents at the start, children at the end
-    let nodes_vec: Vec<Rc<RefCell<FileTreeNode>>> = tree.iter().collect();
-    let root = tree.root();
-    let mut map: HashMap<PathBuf, TreeItem<PathBuf>> = HashMap::new();
-
-    for node in nodes_vec.iter().rev() {
-        if node.borrow().name() == root.borrow().name() {
-            // Skip the root
-            break;
-        }
-        let name = node.borrow().name();
-        let path = node.borrow().path();
-        let name = match node.borrow().ftype() {
+    tree: &FileTree,
+    filesystem: Option<&FileTree>,
+    annotations: Annotations,
+) -> Vec<TreeItem<'a, PathBuf>> {
+    // Items by node id; children come after their parents, so building the items backwards
+    // always finds the items of the children ready
+    let mut items: Vec<Option<TreeItem<PathBuf>>> = Vec::new();
+    items.resize_with(tree.len(), || None);
+
+    for node in tree.iter().skip(1).rev() {
+        let name = node.display_name();
+        let path = node.path();
+        let columns = format!(
+            "{}{} {:>9}  ",
+            type_char(&node.ftype()),
+            node.permissions(),
+            format_size(node.size())
+        );
+        let name = match node.ftype() {
+            EntryType::Directory if node.is_opaque() => name + " (opaque)",
             EntryType::Directory => name,
             EntryType::File => name,
-            EntryType::Symlink(points_to) => name + " -> " + points_to.to_string_lossy().as_ref(),
+            EntryType::Symlink(points_to) => name + " -> " + &escape_name(points_to.as_os_str()),
+            EntryType::Hardlink(target) => name + " => " + &escape_name(target.as_os_str()),
+            EntryType::CharDevice { major, minor } | EntryType::BlockDevice { major, minor } => {
+                format!("{} ({}, {})", name, major, minor)
+            }
+            EntryType::Fifo => name + "|",
+            EntryType::Socket => name + "=",
             EntryType::Badfile => name + " (invalid)",
         };
 
-        let name: Text = match node.borrow().fop() {
-            EntryOp::Add => {
-                let style = Style::new().fg(Color::Green);
-                Text::styled(name, style)
-            }
-            EntryOp::Remove => {
-                let style = Style::new().fg(Color::Red);
-                Text::styled(name, style)
-            }
+        let diff_entry = match annotations {
+            Annotations::Diff(diff) => diff.entry(&path),
+            _ => None,
         };
-
-        if node.borrow().get_n_children() == 0 {
-            let leaf = TreeItem::new_leaf(path.clone(), name);
-            map.insert(path.clone(), leaf);
-        } else {
-            let kids_paths = node.borrow().get_children_paths();
-            // Because we are iterating in the reverse order of breadth-first search, we can assume that the children are already in the map
-            let mut kids_items: Vec<TreeItem<PathBuf>> = Vec::new();
-            for kid in kids_paths.iter() {
-                let kid_item = map.remove(kid);
-                match kid_item {
-                    Some(item) => {
-                        kids_items.push(item);
-                    }
-                    None => {
-                        unreachable!("Kid item that has been in the mapn is not found");
-                    }
+        let name_style = match (diff_entry, node.fop()) {
+            // Directories that are only in a diff because of what is below them
+            (Some(DiffEntry { change: None, .. }), _) => Style::new(),
+            (Some(DiffEntry { change: Some(PathChange::Metadata), .. }), _) => Style::new().fg(Color::Cyan),
+            // Opaque directories replace the directory of the lower layers instead of adding to it
+            (_, EntryOp::Add | EntryOp::Modify) if node.is_opaque() => Style::new().fg(Color::Magenta),
+            (_, EntryOp::Add) => Style::new().fg(Color::Green),
+            (_, EntryOp::Modify) => Style::new().fg(Color::Yellow),
+            (_, EntryOp::Remove) => Style::new().fg(Color::Red),
+        };
+        let mut spans = vec![
+            Span::styled(columns, Style::new().fg(Color::DarkGray)),
+            Span::styled(name, name_style),
+        ];
+        if let (EntryType::Symlink(_), EntryOp::Add | EntryOp::Modify, Some(filesystem)) =
+            (node.ftype(), node.fop(), filesystem)
+        {
+            match filesystem.resolve(node.path()) {
+                LinkTarget::Resolved(_) => {}
+                LinkTarget::Dangling => {
+                    spans.push(Span::styled(" (dangling)", Style::new().fg(Color::Red)));
+                }
+                LinkTarget::Loop => {
+                    spans.push(Span::styled(" (loop)", Style::new().fg(Color::Red)));
                 }
             }
-            let tree_item =
-                TreeItem::new(path.clone(), name, kids_items).expect("Can't create tree item");
-            map.insert(path, tree_item);
         }
+        // Binaries with file capabilities can do privileged things without being setuid
+        if let Some(capabilities) = node.capabilities() {
+            spans.push(Span::styled(
+                format!(" [{}]", capabilities),
+                Style::new().fg(Color::LightRed),
+            ));
+        }
+        // The tree may be filtered, so annotations are looked up by path
+        if let Annotations::Blame(blame, layers) = annotations {
+            if let Some(owner) = blame.owner(&path) {
+                let command = layers
+                    .get(owner)
+                    .map(|layer| layer.short_command())
+                    .unwrap_or_default();
+                spans.push(Span::styled(
+                    format!("  {} {}", ImageLayer::label(owner), command),
+                    Style::new().fg(Color::DarkGray),
+                ));
+            }
+        }
+        if let Some(entry) = diff_entry {
+            let label = match entry.change {
+                Some(change) => format!("  {} {}", change, format_size_delta(entry.size_delta)),
+                None => format!("  {}", format_size_delta(entry.size_delta)),
+            };
+            spans.push(Span::styled(label, Style::new().fg(Color::DarkGray)));
+        }
+        let name = Text::from(Line::from(spans));
+
+        let item = if node.get_n_children() == 0 {
+            TreeItem::new_leaf(path, name)
+        } else {
+            let kids_items = take_children_items(&mut items, node);
+            TreeItem::new(path, name, kids_items).expect("Can't create tree item")
+        };
+        items[node.id()] = Some(item);
     }
 
-    // All that should be left in the map are the nodes below the root
+    // Children are sorted by name, which keeps the tree from jumping in the browser
+    take_children_items(&mut items, tree.root())
+}
+
+fn take_children_items<'a>(
+    items: &mut [Option<TreeItem<'a, PathBuf>>],
+    node: NodeRef,
+) -> Vec<TreeItem<'a, PathBuf>> {
+    node.children()
+        .map(|kid| {
+            items[kid.id()]
+                .take()
+                .expect("Item of a child is built before its parent")
+        })
+        .collect()
+}
+
+/// First character of the `ls -l` mode column
+fn type_char(ftype: &EntryType) -> char {
+    match ftype {
+        EntryType::Directory => 'd',
+        EntryType::Symlink(_) => 'l',
+        EntryType::File | EntryType::Hardlink(_) => '-',
+        EntryType::CharDevice { .. } => 'c',
+        EntryType::BlockDevice { .. } => 'b',
+        EntryType::Fifo => 'p',
+        EntryType::Socket => 's',
+        EntryType::Badfile => '?',
+    }
+}
 
-    let keys: Vec<TreeItem<PathBuf>> = map.into_values().collect();
-    // sort by name to avoid tree jumping in the browser
-    let mut keys = keys;
-    keys.sort_by(|a, b| a.identifier().cmp(b.identifier()));
-    (keys, error)
+/// The tree as the last search left it. Filtering a large tree takes too long to repeat on
+/// every frame, so it is only done again when the search changes; every layer has a state of
+/// its own, which makes the layer part of the key as well.
+struct CachedSearch {
+    mode: SearchMode,
+    search_string: String,
+    // None when the whole tree is shown
+    tree: Option<FileTree>,
+    error: Option<GUIError>,
 }
 
 /// A widget that displays a tree structure using searchbar state; This does not correspond to multiple strings
 pub struct TreeBrowserWidgetState {
     search_string: String,
-    search_error: bool,
+    search_mode: SearchMode,
+    search: Option<CachedSearch>,
     tree_state: TreeState<PathBuf>,
 }
 
 impl TreeBrowserWidgetState {
-    pub fn new(search_string: &str) -> Self {
+    pub fn new(search_string: &str, search_mode: SearchMode) -> Self {
         TreeBrowserWidgetState {
             search_string: search_string.to_string(),
-            search_error: false,
+            search_mode,
+            search: None,
             tree_state: TreeState::default(),
         }
     }
@@ -113,19 +193,100 @@ impl TreeBrowserWidgetState {
         selected_state.toggle_selected();
     }
 
-    pub fn set_search_string(&mut self, search_string: &str) {
+    pub fn set_search(&mut self, search_string: &str, search_mode: SearchMode) {
         self.search_string = search_string.to_string();
+        self.search_mode = search_mode;
+    }
+
+    /// Filters the tree if the search changed since the last time. The directories above
+    /// the matches are opened only then, so that the user can close them again.
+    fn update_search(&mut self, tree: &FileTree) {
+        let unchanged = self.search.as_ref().is_some_and(|search| {
+            search.mode == self.search_mode && search.search_string == self.search_string
+        });
+        if unchanged {
+            return;
+        }
+        let result = search_tree(tree, self.search_mode, &self.search_string);
+        self.expand_matches(&result.matches);
+        self.search = Some(CachedSearch {
+            mode: self.search_mode,
+            search_string: self.search_string.clone(),
+            tree: match result.tree {
+                Cow::Owned(filtered) => Some(filtered),
+                Cow::Borrowed(_) => None,
+            },
+            error: result.error,
+        });
+    }
+
+    /// Opens the directories above the matches of a search
+    fn expand_matches(&mut self, matches: &[PathBuf]) {
+        for path in matches {
+            let identifier = item_identifier(path);
+            for depth in 1..identifier.len() {
+                self.tree_state.open(identifier[..depth].to_vec());
+            }
+        }
+    }
+
+    /// Whether the search string matched nothing or could not be used
+    pub fn search_failed(&self) -> bool {
+        self.search.as_ref().is_some_and(|search| search.error.is_some())
+    }
+
+    /// Why the search string can't be used, e.g. an invalid regex
+    pub fn pattern_error(&self) -> Option<&str> {
+        match self.search.as_ref().and_then(|search| search.error.as_ref()) {
+            Some(GUIError::InvalidPattern(error)) => Some(error),
+            _ => None,
+        }
+    }
+
+    /// Path of the selected entry, as shown to the user
+    pub fn selected_path(&self) -> Option<PathBuf> {
+        self.tree_state.selected().last().cloned()
+    }
+
+    /// Selects the entry at `path`, opening the directories above it
+    pub fn select_path(&mut self, path: &Path) {
+        let identifier = item_identifier(path);
+        for depth in 1..identifier.len() {
+            self.tree_state.open(identifier[..depth].to_vec());
+        }
+        self.tree_state.select(identifier);
+        self.tree_state.scroll_selected_into_view();
     }
 }
 
+/// Items are identified by the paths from the top level down to them
+fn item_identifier(path: &Path) -> Vec<PathBuf> {
+    let mut identifier: Vec<PathBuf> = path
+        .ancestors()
+        .filter(|ancestor| ancestor.parent().is_some())
+        .map(Path::to_path_buf)
+        .collect();
+    identifier.reverse();
+    identifier
+}
+
 pub struct TreeBrowserWidget<'a> {
-    corresponding_layer: &'a ImageLayer,
+    tree: &'a FileTree,
+    // Merged filesystem the symlinks are resolved in
+    filesystem: Option<&'a FileTree>,
+    annotations: Annotations<'a>,
 }
 
 impl<'a> TreeBrowserWidget<'a> {
-    pub fn new(layer: &'a ImageLayer) -> Self {
+    pub fn new(
+        tree: &'a FileTree,
+        filesystem: Option<&'a FileTree>,
+        annotations: Annotations<'a>,
+    ) -> Self {
         TreeBrowserWidget {
-            corresponding_layer: layer,
+            tree,
+            filesystem,
+            annotations,
         }
     }
 }
@@ -152,18 +313,25 @@ impl<'a> StatefulWidget for TreeBrowserWidget<'a> {
     type State = TreeBrowserWidgetState;
 
     fn render(self, area: Rect, buf: &mut Buffer, state: &mut Self::State) {
-        let (items, error) = construct_items(self.corresponding_layer, &state.search_string);
-        match error {
-            Some(GUIError::CantFilterTree) => {
-                state.search_error = true;
-            }
-            None => {
-                state.search_error = false;
-            }
-        }
+        state.update_search(self.tree);
+        let tree = state
+            .search
+            .as_ref()
+            .and_then(|search| search.tree.as_ref())
+            .unwrap_or(self.tree);
+        let items = construct_items(tree, self.filesystem, self.annotations);
 
-        let mut tree_widget = Tree::new(&items)
-            .expect("WTF")
+        // Tree::new only fails on duplicate identifiers; show that instead of taking the app down
+        let tree_widget = match Tree::new(&items) {
+            Ok(tree_widget) => tree_widget,
+            Err(e) => {
+                let message = Paragraph::new(format!("Can't display the tree: {}", e))
+                    .style(Style::default().fg(Color::Red));
+                Widget::render(message, area, buf);
+                return;
+            }
+        };
+        let mut tree_widget = tree_widget
             .highlight_style(
                 Style::default()
                     .bg(Color::Blue)
@@ -172,10 +340,35 @@ impl<'a> StatefulWidget for TreeBrowserWidget<'a> {
             )
             .highlight_symbol(">> ");
 
-        if state.search_error {
+        if state.search_failed() {
             tree_widget = tree_widget.style(Style::default().bg(Color::Red));
         }
 
         StatefulWidget::render(tree_widget, area, buf, &mut state.tree_state);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_tree::test_layer;
+
+    #[test]
+    fn search_is_cached() {
+        let tree = test_layer(&["usr/lib/libssl.so", "etc/hosts"]);
+        let mut state = TreeBrowserWidgetState::new("*.so", SearchMode::Glob);
+        state.update_search(&tree);
+        let filtered_len = state.search.as_ref().and_then(|search| search.tree.as_ref()).map(FileTree::len);
+        assert!(filtered_len.is_some_and(|len| len < tree.len()));
+
+        // The same search is not done again, even for another tree
+        state.update_search(&FileTree::empty());
+        let cached_len = state.search.as_ref().and_then(|search| search.tree.as_ref()).map(FileTree::len);
+        assert_eq!(cached_len, filtered_len);
+
+        state.set_search("(lib", SearchMode::Regex);
+        state.update_search(&tree);
+        assert!(state.search_failed());
+        assert!(state.pattern_error().is_some_and(|error| error.contains("unclosed group")));
+    }
+}